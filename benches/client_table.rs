@@ -0,0 +1,76 @@
+//! Compare the hashed and dense client table layouts of the in-memory state manager.
+//!
+//! Run with `cargo bench --bench client_table`. Events are generated in memory, so only event
+//! application and state emission are measured, along with the memory used by deposit records. The number of events defaults to 5 million and
+//! can be set with the `TRANSACTY_BENCH_EVENTS` environment variable.
+
+mod common;
+
+use std::{collections::HashMap, time::Instant};
+
+use transacty::{
+    primitives::{ClientId, ClientState, Event, EventType},
+    process_events,
+    state::{
+        dense::DenseClientTable,
+        memory::{ClientTable, GenericMemoryState},
+        StateManager,
+    },
+};
+
+use common::Lcg;
+
+const DEFAULT_EVENTS: u32 = 5_000_000;
+
+fn generate(events: u32, clients: u64) -> Vec<Event> {
+    let mut rng = Lcg(0x5eed);
+    (1..=events)
+        .map(|tx| {
+            let event_type = match rng.next() % 10 {
+                0..=5 => EventType::Deposit,
+                6..=8 => EventType::Withdrawal,
+                _ => EventType::Dispute,
+            };
+            let amount = format!("{}.{:04}", rng.next() % 1000, 1 + rng.next() % 9_999);
+            Event {
+                event_type,
+                client: ((rng.next() % clients) as u16).into(),
+                tx: tx.into(),
+                amount: amount.parse().expect("generated amounts are valid"),
+            }
+        })
+        .collect()
+}
+
+fn bench<Clients: ClientTable + Default>(name: &str, events: &[Event]) {
+    let start = Instant::now();
+    let mut state = GenericMemoryState::<Clients>::default();
+    process_events(&mut state, events.iter().cloned(), None);
+    let processed = start.elapsed();
+
+    let start = Instant::now();
+    let emitted = state.emit_state().count();
+    let emission = start.elapsed();
+
+    println!(
+        "{name:<8} process {:>8.3}s ({:>10.0} events/s)   emit {emitted:>6} clients {:>9.3}ms",
+        processed.as_secs_f64(),
+        events.len() as f64 / processed.as_secs_f64(),
+        emission.as_secs_f64() * 1000.0,
+    );
+    println!("{name:<8} {}", state.deposit_stats());
+}
+
+fn main() {
+    let events = std::env::var("TRANSACTY_BENCH_EVENTS")
+        .ok()
+        .and_then(|events| events.parse().ok())
+        .unwrap_or(DEFAULT_EVENTS);
+
+    for clients in [100, 10_000, 65_536] {
+        println!("{events} events across {clients} clients");
+        let generated = generate(events, clients);
+        bench::<HashMap<ClientId, ClientState>>("hash", &generated);
+        bench::<DenseClientTable>("dense", &generated);
+    }
+}
@@ -0,0 +1,14 @@
+//! Helpers shared by the benchmarks.
+
+/// A tiny deterministic generator, so that every run sees identical input.
+pub struct Lcg(pub u64);
+
+impl Lcg {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+}
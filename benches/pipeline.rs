@@ -0,0 +1,96 @@
+//! Compare the throughput of inline and pipelined parsing over a large generated input.
+//!
+//! Run with `cargo bench --bench pipeline`. The number of rows defaults to 3 million and can be
+//! set with the `TRANSACTY_BENCH_ROWS` environment variable.
+
+mod common;
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+use transacty::{
+    input::{read_events, ParseError},
+    mapping::InputMapping,
+    pipeline::{parse_pipelined, PipelineConfig},
+    primitives::Event,
+    process_events,
+    state::memory::MemoryState,
+};
+
+use common::Lcg;
+
+const DEFAULT_ROWS: u64 = 3_000_000;
+
+fn generate(path: &Path, rows: u64) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut rng = Lcg(0x5eed);
+    writeln!(out, "type, client, tx, amount")?;
+    for tx in 1..=rows {
+        let client = rng.next() % 10_000;
+        let amount = format!("{}.{:04}", rng.next() % 1000, rng.next() % 10_000);
+        match rng.next() % 10 {
+            0..=5 => writeln!(out, "deposit, {client}, {tx}, {amount}")?,
+            6..=8 => writeln!(out, "withdrawal, {client}, {tx}, {amount}")?,
+            _ => writeln!(out, "dispute, {client}, {}, ", rng.next() % tx + 1)?,
+        }
+    }
+    out.flush()
+}
+
+fn open(path: &Path) -> File {
+    File::open(path).expect("benchmark input was just generated")
+}
+
+fn run(name: &str, rows: u64, events: impl Iterator<Item = Result<Event, ParseError>>) {
+    let start = Instant::now();
+    let mut state = MemoryState::default();
+    process_events(
+        &mut state,
+        events.map(|event| event.expect("benchmark input is valid")),
+        None,
+    );
+    let elapsed = start.elapsed();
+    println!(
+        "{name:<24} {:>8.3}s {:>12.0} rows/s",
+        elapsed.as_secs_f64(),
+        rows as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() -> std::io::Result<()> {
+    let rows = std::env::var("TRANSACTY_BENCH_ROWS")
+        .ok()
+        .and_then(|rows| rows.parse().ok())
+        .unwrap_or(DEFAULT_ROWS);
+
+    let path = std::env::temp_dir().join(format!("transacty-bench-{rows}.csv"));
+    if !path.exists() {
+        generate(&path, rows)?;
+    }
+
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    println!("{rows} rows, {cores} cores available");
+
+    run(
+        "inline",
+        rows,
+        read_events(&InputMapping::default(), open(&path)),
+    );
+    for parsers in [1, 2, 4] {
+        let config = PipelineConfig {
+            parsers,
+            ..PipelineConfig::default()
+        };
+        run(
+            &format!("pipelined ({parsers} parsers)"),
+            rows,
+            parse_pipelined(open(&path), InputMapping::default(), config),
+        );
+    }
+
+    Ok(())
+}
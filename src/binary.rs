@@ -0,0 +1,392 @@
+//! A compact binary encoding of events and client state, for transport between internal services.
+//!
+//! A stream begins with a header: the magic bytes `TXBIN`, the format version as a little-endian
+//! `u16`, and a byte identifying the kind of record which follows. Each record is then prefixed by
+//! its length, also a little-endian `u16`. Fields are little-endian, and amounts are written as
+//! their raw fixed-point value, so nothing is lost in transit.
+//!
+//! Readers ignore any bytes of a record beyond the fields they know, so fields may be appended to
+//! a record without a new version. Any other change to the layout of a record must bump `VERSION`.
+//! Because every record carries its length, a reader can skip a record it cannot decode and carry
+//! on with the next.
+
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    marker::PhantomData,
+};
+
+use crate::{
+    primitives::{Amount, ClientId, Event, SerializeClientState},
+    state::snapshot::{
+        read_amount, read_event, read_u16, read_u8, write_amount, write_event, write_u16, write_u8,
+    },
+};
+
+/// The bytes which begin every binary stream.
+pub const MAGIC: &[u8; 5] = b"TXBIN";
+
+/// The version of the encoding written by this program.
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BinaryError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("input is not a binary stream")]
+    NotBinary,
+    #[error("binary format version {0} is not supported; expected version {VERSION}")]
+    UnsupportedVersion(u16),
+    #[error("stream holds {found} records, not {expected} records")]
+    WrongKind {
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[error("record is truncated")]
+    Truncated,
+    #[error("record is invalid: {0}")]
+    Invalid(String),
+}
+
+/// A Record can be written to and read from a binary stream.
+pub trait Record: Sized {
+    /// The byte identifying streams of this kind of record.
+    const KIND: u8;
+    /// What this kind of record is called, for error messages.
+    const NAME: &'static str;
+
+    /// Append the encoded fields of this record to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decode a record from its encoded fields, ignoring any which follow those it knows.
+    fn decode(input: &[u8]) -> io::Result<Self>;
+}
+
+/// The name of the kind of record identified by a byte, if it is known.
+fn kind_name(kind: u8) -> &'static str {
+    match kind {
+        Event::KIND => Event::NAME,
+        SerializeClientState::KIND => SerializeClientState::NAME,
+        _ => "unknown",
+    }
+}
+
+/// Events are encoded as their type, client, transaction id, and amount: 15 bytes.
+impl Record for Event {
+    const KIND: u8 = 0;
+    const NAME: &'static str = "event";
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_event(out, self).expect("writing to a vec succeeds");
+    }
+
+    fn decode(mut input: &[u8]) -> io::Result<Self> {
+        read_event(&mut input)
+    }
+}
+
+/// Client state is encoded as the client, available and held funds, and whether the account is
+/// locked: 19 bytes. The total is not sent; it is the sum of the others.
+impl Record for SerializeClientState {
+    const KIND: u8 = 1;
+    const NAME: &'static str = "client state";
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut encode = || -> io::Result<()> {
+            write_u16(out, self.client.into())?;
+            write_amount(out, self.available)?;
+            write_amount(out, self.held)?;
+            write_u8(out, self.locked.into())
+        };
+        encode().expect("writing to a vec succeeds");
+    }
+
+    fn decode(mut input: &[u8]) -> io::Result<Self> {
+        let input = &mut input;
+        let client = ClientId::from(read_u16(input)?);
+        let available = read_amount(input)?;
+        let held = read_amount(input)?;
+        let locked = match read_u8(input)? {
+            0 => false,
+            1 => true,
+            _ => {
+                return Err(crate::state::snapshot::invalid_data(
+                    "locked is not a boolean",
+                ))
+            }
+        };
+        let total = available
+            .to_raw()
+            .checked_add(held.to_raw())
+            .map(Amount::from_raw)
+            .ok_or_else(|| crate::state::snapshot::invalid_data("total is out of range"))?;
+        Ok(SerializeClientState {
+            client,
+            available,
+            held,
+            total,
+            locked,
+        })
+    }
+}
+
+/// Writer writes a binary stream of records.
+pub struct Writer<W: Write, T> {
+    out: BufWriter<W>,
+    buf: Vec<u8>,
+    _record: PhantomData<fn(&T)>,
+}
+
+impl<W: Write, T: Record> Writer<W, T> {
+    /// Begin a stream by writing its header.
+    pub fn new(out: W) -> io::Result<Self> {
+        let mut out = BufWriter::new(out);
+        out.write_all(MAGIC)?;
+        write_u16(&mut out, VERSION)?;
+        write_u8(&mut out, T::KIND)?;
+        Ok(Writer {
+            out,
+            buf: Vec::new(),
+            _record: PhantomData,
+        })
+    }
+
+    pub fn write(&mut self, record: &T) -> io::Result<()> {
+        self.buf.clear();
+        record.encode(&mut self.buf);
+        let len = u16::try_from(self.buf.len())
+            .expect("records are encoded in far fewer than 65536 bytes");
+        write_u16(&mut self.out, len)?;
+        self.out.write_all(&self.buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Flush the stream and return the writer it was written to.
+    pub fn finish(self) -> io::Result<W> {
+        self.out
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)
+    }
+}
+
+/// Reader reads a binary stream of records, yielding each one in turn.
+///
+/// A record which cannot be decoded yields an error, and reading carries on with the next. Reading
+/// stops after an I/O error or a truncated record.
+pub struct Reader<R: Read, T> {
+    input: BufReader<R>,
+    buf: Vec<u8>,
+    done: bool,
+    _record: PhantomData<fn() -> T>,
+}
+
+impl<R: Read, T: Record> Reader<R, T> {
+    /// Read the header of a stream, checking that it holds records of this kind.
+    pub fn new(input: R) -> Result<Self, BinaryError> {
+        let mut input = BufReader::new(input);
+        let mut magic = [0; MAGIC.len()];
+        if read_fully(&mut input, &mut magic)? < magic.len() || &magic != MAGIC {
+            return Err(BinaryError::NotBinary);
+        }
+        let version = read_u16(&mut input).map_err(truncated)?;
+        if version != VERSION {
+            return Err(BinaryError::UnsupportedVersion(version));
+        }
+        let kind = read_u8(&mut input).map_err(truncated)?;
+        if kind != T::KIND {
+            return Err(BinaryError::WrongKind {
+                expected: T::NAME,
+                found: kind_name(kind),
+            });
+        }
+        Ok(Reader {
+            input,
+            buf: Vec::new(),
+            done: false,
+            _record: PhantomData,
+        })
+    }
+
+    fn read_record(&mut self) -> Option<Result<T, BinaryError>> {
+        let mut len = [0; 2];
+        match read_fully(&mut self.input, &mut len) {
+            Ok(0) => return None,
+            Ok(2) => {}
+            Ok(_) => return Some(Err(BinaryError::Truncated)),
+            Err(err) => return Some(Err(err.into())),
+        }
+        self.buf.resize(u16::from_le_bytes(len).into(), 0);
+        match read_fully(&mut self.input, &mut self.buf) {
+            Ok(read) if read == self.buf.len() => {}
+            Ok(_) => return Some(Err(BinaryError::Truncated)),
+            Err(err) => return Some(Err(err.into())),
+        }
+        Some(T::decode(&self.buf).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => BinaryError::Truncated,
+            _ => BinaryError::Invalid(err.to_string()),
+        }))
+    }
+}
+
+impl<R: Read, T: Record> Iterator for Reader<R, T> {
+    type Item = Result<T, BinaryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.read_record();
+        // a record which was read whole but could not be decoded can be skipped
+        if !matches!(record, Some(Ok(_)) | Some(Err(BinaryError::Invalid(_)))) {
+            self.done = true;
+        }
+        record
+    }
+}
+
+/// A header cut short is not a binary stream.
+fn truncated(err: io::Error) -> BinaryError {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => BinaryError::NotBinary,
+        _ => err.into(),
+    }
+}
+
+/// Fill `buf` from `input`, returning how much was read before the end of input.
+fn read_fully<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{
+        tests::{arb_amount, arb_client_id, arb_event},
+        ClientState,
+    };
+    use proptest::prelude::*;
+
+    fn encoded<T: Record>(records: &[T]) -> Vec<u8> {
+        let mut writer = Writer::new(Vec::new()).expect("writing to a vec succeeds");
+        for record in records {
+            writer.write(record).expect("writing to a vec succeeds");
+        }
+        writer.finish().expect("writing to a vec succeeds")
+    }
+
+    fn decoded<T: Record>(bytes: &[u8]) -> Result<Vec<Result<T, BinaryError>>, BinaryError> {
+        Ok(Reader::new(bytes)?.collect())
+    }
+
+    prop_compose! {
+        fn arb_client_state()
+        (
+            client in arb_client_id(u16::MAX),
+            available in arb_amount(1e9),
+            held in arb_amount(1e9),
+            locked in any::<bool>(),
+        ) -> SerializeClientState {
+            ClientState { available, held, locked }.to_serialize(client)
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn events_round_trip(events in proptest::collection::vec(arb_event(u16::MAX, 1e12), 0..100)) {
+            let bytes = encoded(&events);
+            let read: Vec<Event> = Reader::new(bytes.as_slice())
+                .expect("header is valid")
+                .collect::<Result<_, _>>()
+                .expect("records are valid");
+            prop_assert_eq!(read, events);
+        }
+
+        #[test]
+        fn client_states_round_trip(clients in proptest::collection::vec(arb_client_state(), 0..100)) {
+            let bytes = encoded(&clients);
+            let read: Vec<SerializeClientState> = Reader::new(bytes.as_slice())
+                .expect("header is valid")
+                .collect::<Result<_, _>>()
+                .expect("records are valid");
+            prop_assert_eq!(read, clients);
+        }
+    }
+
+    fn event(tx: u32) -> Event {
+        Event {
+            event_type: crate::primitives::EventType::Deposit,
+            client: 1.into(),
+            tx: tx.into(),
+            amount: "1.5".parse().expect("valid amount"),
+        }
+    }
+
+    #[test]
+    fn events_are_compact() {
+        let bytes = encoded(&[event(1), event(2)]);
+        assert_eq!(bytes.len(), MAGIC.len() + 3 + 2 * (2 + 15));
+    }
+
+    #[test]
+    fn invalid_records_are_skipped_and_truncation_stops_reading() {
+        let mut bytes = encoded(&[event(1), event(2), event(3)]);
+        // corrupt the event type of the second record
+        let header = MAGIC.len() + 3;
+        bytes[header + 17 + 2] = 99;
+        // and cut the third short
+        bytes.pop();
+
+        let read = decoded::<Event>(&bytes).expect("header is valid");
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0].as_ref().expect("first record is valid"), &event(1));
+        assert!(matches!(read[1], Err(BinaryError::Invalid(_))));
+        assert!(matches!(read[2], Err(BinaryError::Truncated)));
+    }
+
+    #[test]
+    fn appended_fields_are_ignored() {
+        let mut bytes = encoded::<Event>(&[]);
+        let mut record = Vec::new();
+        event(1).encode(&mut record);
+        record.extend_from_slice(b"from a later version");
+        bytes.extend_from_slice(&(record.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&record);
+
+        let read = decoded::<Event>(&bytes).expect("header is valid");
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].as_ref().expect("record is valid"), &event(1));
+    }
+
+    #[test]
+    fn headers_are_checked() {
+        assert!(matches!(
+            decoded::<Event>(b"type,client,tx,amount\n"),
+            Err(BinaryError::NotBinary)
+        ));
+        assert!(matches!(
+            decoded::<Event>(b"TXB"),
+            Err(BinaryError::NotBinary)
+        ));
+        assert!(matches!(
+            decoded::<Event>(b"TXBIN\x02\x00\x00"),
+            Err(BinaryError::UnsupportedVersion(2))
+        ));
+        let clients = encoded::<SerializeClientState>(&[]);
+        let err = decoded::<Event>(&clients).expect_err("stream holds client state");
+        assert_eq!(
+            err.to_string(),
+            "stream holds client state records, not event records"
+        );
+    }
+}
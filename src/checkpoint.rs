@@ -0,0 +1,329 @@
+//! Checkpoints of a run over a single input, so that an interrupted run can be resumed.
+//!
+//! A checkpoint records the position in the input just after the last record which was fed to the
+//! engine, the counts of what had been read up to there, and a snapshot of the state with exactly
+//! those records applied. Resuming restores the snapshot and seeks the input to the position, so no
+//! event is applied twice, and none is skipped.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    input::Position,
+    primitives::{EventType, SerializeClientState},
+    state::{
+        memory::MemoryState,
+        snapshot::{
+            invalid_data, read_u16, read_u32, read_u64, read_u8, write_u16, write_u64, Snapshot,
+            SHARDED_TAG,
+        },
+        StateManager,
+    },
+    ProcessCounts, Reason,
+};
+
+/// Checkpoint files begin with these bytes.
+pub const MAGIC: &[u8; 6] = b"TXCKPT";
+/// The version of the checkpoint format, which follows the magic bytes.
+const VERSION: u16 = 2;
+
+/// Checkpoint describes how far through its input a run had reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The position just after the last record fed to the engine.
+    pub position: Position,
+    /// The counts of every record up to `position`.
+    pub counts: ProcessCounts,
+}
+
+impl Checkpoint {
+    /// Write this checkpoint, with a snapshot of `state`, to `path`.
+    ///
+    /// The checkpoint is written beside `path` and synced before it replaces any previous
+    /// checkpoint, so a crash while writing leaves the previous checkpoint intact.
+    pub fn write<S>(&self, path: &Path, state: &S) -> io::Result<()>
+    where
+        S: Snapshot + ?Sized,
+    {
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(".partial");
+        let partial = path.with_file_name(name);
+
+        let mut out = BufWriter::new(File::create(&partial)?);
+        out.write_all(MAGIC)?;
+        write_u16(&mut out, VERSION)?;
+        self.write_body(&mut out)?;
+        state.write_snapshot(&mut out)?;
+        out.into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+        std::fs::rename(&partial, path)
+    }
+
+    fn write_body(&self, out: &mut dyn Write) -> io::Result<()> {
+        let Position { byte, line, record } = self.position;
+        for value in [byte, line, record] {
+            write_u64(out, value)?;
+        }
+        let counts = &self.counts;
+        write_u64(out, counts.records as u64)?;
+        write_u64(out, counts.errors as u64)?;
+        for (_, count) in counts.reasons.iter() {
+            write_u64(out, count as u64)?;
+        }
+        for (_, count) in counts.event_types.iter() {
+            write_u64(out, count as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Read a checkpoint from `path`, restoring `state` from its snapshot.
+    pub fn read<S>(path: &Path, state: &mut S) -> io::Result<Self>
+    where
+        S: Snapshot + ?Sized,
+    {
+        let (checkpoint, mut input) = Self::open(path)?;
+        state.read_snapshot(&mut input)?;
+        Ok(checkpoint)
+    }
+
+    /// Read a checkpoint from `path`, along with the state of each client in its snapshot.
+    ///
+    /// Like `read_client_view`, this needs no state to restore into, and skips over deposit
+    /// records and deferred references; the history is skipped too.
+    pub fn read_clients(path: &Path) -> io::Result<(Self, Vec<SerializeClientState>)> {
+        let (checkpoint, state) = Self::read_into(path, MemoryState::default())?;
+        Ok((checkpoint, state.emit_state().collect()))
+    }
+
+    /// Read a checkpoint from `path`, along with the clients in its snapshot and any history kept
+    /// of them, into a new state.
+    ///
+    /// Unlike `read`, this needs neither a state configured as the run's was nor room for its
+    /// deposit records, which are skipped over; the state returned can be queried about its
+    /// clients, but cannot carry on processing. A sharded snapshot is read into a single state.
+    pub fn read_client_view(path: &Path) -> io::Result<(Self, MemoryState)> {
+        Self::read_into(path, MemoryState::default().with_history())
+    }
+
+    /// Read a checkpoint from `path`, adding the clients in its snapshot to `state`.
+    fn read_into(path: &Path, mut state: MemoryState) -> io::Result<(Self, MemoryState)> {
+        let (checkpoint, mut input) = Self::open(path)?;
+        let tag = read_u8(&mut input)?;
+        if tag == SHARDED_TAG {
+            let shards = read_u64(&mut input)?;
+            // the owning client of each deposit, by which references were routed
+            for _ in 0..read_u64(&mut input)? {
+                read_u32(&mut input)?;
+                read_u16(&mut input)?;
+            }
+            for _ in 0..shards {
+                state.read_client_snapshot(&mut input)?;
+            }
+        } else {
+            state.read_client_snapshot(&mut [tag].as_slice().chain(input))?;
+        }
+        Ok((checkpoint, state))
+    }
+
+    /// Open the checkpoint at `path`, reading everything up to its snapshot.
+    fn open(path: &Path) -> io::Result<(Self, BufReader<File>)> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a checkpoint file"));
+        }
+        let version = read_u16(&mut input)?;
+        if version != VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported checkpoint version {version}"
+            )));
+        }
+        let checkpoint = Self::read_body(&mut input)?;
+        Ok((checkpoint, input))
+    }
+
+    fn read_body(input: &mut dyn Read) -> io::Result<Self> {
+        let position = Position {
+            byte: read_u64(input)?,
+            line: read_u64(input)?,
+            record: read_u64(input)?,
+        };
+        let mut counts = ProcessCounts {
+            records: read_u64(input)? as usize,
+            errors: read_u64(input)? as usize,
+            ..ProcessCounts::default()
+        };
+        for reason in Reason::ALL {
+            counts.reasons[reason] = read_u64(input)? as usize;
+        }
+        for event_type in EventType::ALL {
+            counts.event_types[event_type] = read_u64(input)? as usize;
+        }
+        Ok(Checkpoint { position, counts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        feed_records,
+        input::read_events,
+        mapping::InputMapping,
+        process_records,
+        sharded::ShardedState,
+        state::{
+            deposits::SpillConfig,
+            memory::{MemoryState, PendingConfig},
+            SortKey, StateManager,
+        },
+    };
+
+    #[test]
+    fn checkpoints_round_trip() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10.0\n\
+            dispute,1,1,\n\
+            withdrawal,1,2,5.0\n\
+            refund,1,3,1.0\n";
+        let mut state = MemoryState::default();
+        let counts = process_records(
+            &mut state,
+            read_events(&InputMapping::default(), input.as_bytes()),
+            None,
+        );
+        let checkpoint = Checkpoint {
+            position: Position {
+                byte: input.len() as u64,
+                line: 6,
+                record: 4,
+            },
+            counts,
+        };
+
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.bin", std::process::id()));
+        checkpoint
+            .write(&path, &state)
+            .expect("temp dir is writable");
+        let mut restored = MemoryState::default();
+        let read = Checkpoint::read(&path, &mut restored);
+        std::fs::remove_file(&path).expect("checkpoint was just written");
+
+        assert_eq!(read.expect("checkpoint was just written"), checkpoint);
+        assert_eq!(counts.reasons[Reason::InsufficientFunds], 1);
+        assert_eq!(
+            restored.emit_sorted(SortKey::Client).collect::<Vec<_>>(),
+            state.emit_sorted(SortKey::Client).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn client_views_skip_deposits_and_deferred_references() {
+        // enough deposits to spill, and a reference to tx 9 which stays deferred
+        let mut input = "type,client,tx,amount\n\
+            deposit,1,1,10.0\n\
+            dispute,2,9,\n\
+            deposit,2,2,3.0\n\
+            dispute,1,1,\n"
+            .to_owned();
+        for tx in 100..200 {
+            input.push_str(&format!("deposit,3,{tx},1.0\n"));
+        }
+        let checkpoint = Checkpoint {
+            position: Position {
+                byte: input.len() as u64,
+                line: 105,
+                record: 104,
+            },
+            counts: ProcessCounts::default(),
+        };
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.view", std::process::id()));
+        let spill = SpillConfig {
+            memory_ceiling: 1,
+            directory: std::env::temp_dir(),
+        };
+        let mut state =
+            MemoryState::new(Some(PendingConfig::default()), Some(spill)).with_history();
+        feed_records(
+            &mut state,
+            read_events(&InputMapping::default(), input.as_bytes()),
+            None,
+        );
+        assert!(state.deposit_stats().spill_files > 0);
+        checkpoint
+            .write(&path, &state)
+            .expect("temp dir is writable");
+        let read = Checkpoint::read_client_view(&path);
+        std::fs::remove_file(&path).expect("checkpoint was just written");
+
+        let (read, view) = read.expect("checkpoint was just written");
+        assert_eq!(read, checkpoint);
+        assert_eq!(
+            view.emit_sorted(SortKey::Client).collect::<Vec<_>>(),
+            state.emit_sorted(SortKey::Client).collect::<Vec<_>>()
+        );
+        assert_eq!(view.deposit_stats().in_memory, 0);
+        let history = |state: &MemoryState| {
+            state
+                .history(1.into())
+                .expect("history is kept")
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(history(&view).len(), 2);
+        assert_eq!(history(&view), history(&state));
+    }
+
+    #[test]
+    fn clients_are_read_from_either_kind_of_snapshot() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10.0\n\
+            deposit,2,2,3.0\n\
+            dispute,1,1,\n\
+            deposit,3,3,1.5\n\
+            dispute,3,9,\n";
+        let checkpoint = Checkpoint {
+            position: Position {
+                byte: 0,
+                line: 1,
+                record: 0,
+            },
+            counts: ProcessCounts::default(),
+        };
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.clients", std::process::id()));
+        let read_back = |state: &dyn Snapshot| {
+            checkpoint
+                .write(&path, state)
+                .expect("temp dir is writable");
+            let read = Checkpoint::read_clients(&path);
+            std::fs::remove_file(&path).expect("checkpoint was just written");
+            let (_, mut clients) = read.expect("checkpoint was just written");
+            clients.sort_by_key(|client| client.client);
+            clients
+        };
+
+        // the reference to tx 9 stays deferred, which the state read into need not allow
+        let make_state = || MemoryState::with_pending(PendingConfig::default()).with_history();
+        let mut state = make_state();
+        feed_records(
+            &mut state,
+            read_events(&InputMapping::default(), input.as_bytes()),
+            None,
+        );
+        let expected: Vec<_> = state.emit_sorted(SortKey::Client).collect();
+        assert_eq!(expected.len(), 3);
+        assert_eq!(read_back(&state), expected);
+
+        let mut sharded = ShardedState::new(2, make_state);
+        feed_records(
+            &mut sharded,
+            read_events(&InputMapping::default(), input.as_bytes()),
+            None,
+        );
+        assert_eq!(read_back(&sharded), expected);
+    }
+}
@@ -0,0 +1,277 @@
+//! Exporting client state and event history to Parquet, for loading into columnar tools.
+//!
+//! Amounts are written as `DECIMAL(20, 4)` columns: their raw fixed-point value is the unscaled
+//! decimal, so nothing is rounded through a float. Twenty digits hold any `u64`.
+//!
+//! Only available with the `parquet` feature.
+
+use std::{io::Write, sync::Arc};
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Decimal128Array, RecordBatch, StringArray, UInt16Array, UInt32Array,
+    UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    errors::ParquetError,
+    file::properties::WriterProperties,
+};
+
+use crate::primitives::{Amount, Event, SerializeClientState};
+
+/// The precision of amount columns: the number of digits in `u64::MAX`.
+pub const AMOUNT_PRECISION: u8 = 20;
+/// The scale of amount columns: amounts have four decimal places.
+pub const AMOUNT_SCALE: i8 = 4;
+
+/// Rows are buffered and written in row groups of this many.
+const BATCH_ROWS: usize = 64 * 1024;
+
+fn amount_type() -> DataType {
+    DataType::Decimal128(AMOUNT_PRECISION, AMOUNT_SCALE)
+}
+
+fn amounts(values: impl Iterator<Item = Option<Amount>>) -> Result<ArrayRef, ParquetError> {
+    let array = values
+        .map(|amount| amount.map(|amount| i128::from(amount.to_raw())))
+        .collect::<Decimal128Array>()
+        .with_precision_and_scale(AMOUNT_PRECISION, AMOUNT_SCALE)?;
+    Ok(Arc::new(array))
+}
+
+fn properties() -> WriterProperties {
+    WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .set_max_row_group_size(BATCH_ROWS)
+        .build()
+}
+
+/// The schema of exported client state: `client`, `available`, `held`, `total`, and `locked`.
+pub fn client_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", amount_type(), false),
+        Field::new("held", amount_type(), false),
+        Field::new("total", amount_type(), false),
+        Field::new("locked", DataType::Boolean, false),
+    ]))
+}
+
+/// Write each client to `writer` as a Parquet file.
+///
+/// Clients are buffered into row groups, so the state is never collected whole.
+pub fn write_clients<W, I>(clients: I, writer: W) -> Result<W, ParquetError>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = SerializeClientState>,
+{
+    let schema = client_schema();
+    let mut writer = ArrowWriter::try_new(writer, schema.clone(), Some(properties()))?;
+    let mut clients = clients.into_iter().peekable();
+    let mut batch = Vec::with_capacity(BATCH_ROWS);
+    while clients.peek().is_some() {
+        batch.clear();
+        batch.extend((&mut clients).take(BATCH_ROWS));
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(
+                batch
+                    .iter()
+                    .map(|c| u16::from(c.client))
+                    .collect::<UInt16Array>(),
+            ),
+            amounts(batch.iter().map(|c| Some(c.available)))?,
+            amounts(batch.iter().map(|c| Some(c.held)))?,
+            amounts(batch.iter().map(|c| Some(c.total)))?,
+            Arc::new(
+                batch
+                    .iter()
+                    .map(|c| Some(c.locked))
+                    .collect::<BooleanArray>(),
+            ),
+        ];
+        writer.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+    }
+    writer.into_inner()
+}
+
+/// The schema of exported event history: `seq`, `type`, `client`, `tx`, and `amount`.
+///
+/// `seq` numbers events in the order they were processed, from 1. `amount` is null for events
+/// which carry none.
+pub fn event_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("seq", DataType::UInt64, false),
+        Field::new("type", DataType::Utf8, false),
+        Field::new("client", DataType::UInt16, false),
+        Field::new("tx", DataType::UInt32, false),
+        Field::new("amount", amount_type(), true),
+    ]))
+}
+
+/// EventWriter writes the history of processed events to a Parquet file.
+///
+/// Events are buffered until a row group is full; `finish` must be called to write the last of
+/// them, along with the file footer.
+pub struct EventWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    schema: SchemaRef,
+    buffer: Vec<Event>,
+    written: u64,
+}
+
+impl<W: Write + Send> EventWriter<W> {
+    pub fn new(writer: W) -> Result<Self, ParquetError> {
+        let schema = event_schema();
+        Ok(EventWriter {
+            writer: ArrowWriter::try_new(writer, schema.clone(), Some(properties()))?,
+            schema,
+            buffer: Vec::with_capacity(BATCH_ROWS),
+            written: 0,
+        })
+    }
+
+    pub fn write(&mut self, event: &Event) -> Result<(), ParquetError> {
+        self.buffer.push(event.clone());
+        if self.buffer.len() == BATCH_ROWS {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> Result<(), ParquetError> {
+        let events = &self.buffer;
+        let first = self.written + 1;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from_iter_values(
+                first..first + events.len() as u64,
+            )),
+            Arc::new(
+                events
+                    .iter()
+                    .map(|e| Some(e.event_type.name()))
+                    .collect::<StringArray>(),
+            ),
+            Arc::new(
+                events
+                    .iter()
+                    .map(|e| u16::from(e.client))
+                    .collect::<UInt16Array>(),
+            ),
+            Arc::new(
+                events
+                    .iter()
+                    .map(|e| u32::from(e.tx))
+                    .collect::<UInt32Array>(),
+            ),
+            amounts(
+                events
+                    .iter()
+                    .map(|e| (e.has_amount() || !e.amount.is_zero()).then_some(e.amount)),
+            )?,
+        ];
+        self.writer
+            .write(&RecordBatch::try_new(self.schema.clone(), columns)?)?;
+        self.written += events.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Write any buffered events and the file footer, returning the underlying writer.
+    pub fn finish(mut self) -> Result<W, ParquetError> {
+        self.write_batch()?;
+        self.writer.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{
+        tests::{arb_amount, arb_client_id, arb_event},
+        ClientState,
+    };
+    use arrow_array::{cast::AsArray, types::Decimal128Type};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use proptest::prelude::*;
+
+    fn read_back(bytes: Vec<u8>) -> Vec<RecordBatch> {
+        ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(bytes))
+            .expect("file is valid parquet")
+            .build()
+            .expect("file is valid parquet")
+            .collect::<Result<_, _>>()
+            .expect("batches are valid")
+    }
+
+    fn raw_amounts(batch: &RecordBatch, column: &str) -> Vec<Option<u64>> {
+        batch
+            .column_by_name(column)
+            .expect("column exists")
+            .as_primitive::<Decimal128Type>()
+            .iter()
+            .map(|value| value.map(|value| u64::try_from(value).expect("amounts fit a u64")))
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn events_export_exactly(events in proptest::collection::vec(arb_event(u16::MAX, 1e12), 1..200)) {
+            let mut writer = EventWriter::new(Vec::new()).expect("schema is valid");
+            for event in &events {
+                writer.write(event).expect("writing to a vec succeeds");
+            }
+            let batches = read_back(writer.finish().expect("writing to a vec succeeds"));
+            // small files are read back as a single batch
+            prop_assert_eq!(batches.len(), 1);
+            let batch = &batches[0];
+            prop_assert_eq!(batch.num_rows(), events.len());
+            let types = batch.column_by_name("type").expect("column exists").as_string::<i32>();
+            let expected: Vec<_> = events
+                .iter()
+                .map(|e| (e.has_amount() || !e.amount.is_zero()).then_some(e.amount.to_raw()))
+                .collect();
+            prop_assert_eq!(raw_amounts(batch, "amount"), expected);
+            for (index, event) in events.iter().enumerate() {
+                prop_assert_eq!(types.value(index), event.event_type.name());
+            }
+        }
+
+        #[test]
+        fn clients_export_exactly(
+            clients in proptest::collection::vec(
+                (arb_client_id(u16::MAX), arb_amount(1e12), arb_amount(1e12), any::<bool>()),
+                0..200,
+            )
+        ) {
+            let clients: Vec<SerializeClientState> = clients
+                .into_iter()
+                .map(|(client, available, held, locked)| {
+                    ClientState { available, held, locked }.to_serialize(client)
+                })
+                .collect();
+            let bytes = write_clients(clients.clone(), Vec::new()).expect("writing to a vec succeeds");
+            let batches = read_back(bytes);
+            let rows: usize = batches.iter().map(RecordBatch::num_rows).sum();
+            prop_assert_eq!(rows, clients.len());
+            if let Some(batch) = batches.first() {
+                let totals: Vec<_> = clients.iter().map(|c| Some(c.total.to_raw())).collect();
+                prop_assert_eq!(raw_amounts(batch, "total"), totals);
+            }
+        }
+    }
+
+    #[test]
+    fn amounts_are_decimal_columns() {
+        let schema = event_schema();
+        let amount = schema.field_with_name("amount").expect("column exists");
+        assert_eq!(amount.data_type(), &DataType::Decimal128(20, 4));
+        assert!(amount.is_nullable());
+        let schema = client_schema();
+        for column in ["available", "held", "total"] {
+            let field = schema.field_with_name(column).expect("column exists");
+            assert_eq!(field.data_type(), &DataType::Decimal128(20, 4));
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Diffs between two sets of client state, such as the output of a run before and after a change
+//! in policy.
+//!
+//! A client absent from one set is compared as though it had an empty account there, so its funds
+//! count as a movement. A lock change is only reported for a client in both sets; a client added or
+//! removed while locked changed presence, not lock.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{
+    binary::{self, BinaryError},
+    checkpoint::{self, Checkpoint},
+    primitives::{AmountDelta, ClientId, ClientState, SerializeClientState},
+    reconcile::{read_clients, ReconcileError},
+};
+
+/// How a client's presence differs between the two sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    /// The client is in both sets.
+    Both,
+    /// The client is only in the second set.
+    Added,
+    /// The client is only in the first set.
+    Removed,
+}
+
+impl Presence {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Presence::Both => "changed",
+            Presence::Added => "added",
+            Presence::Removed => "removed",
+        }
+    }
+}
+
+/// A change in whether a client's account is locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockChange {
+    Locked,
+    Unlocked,
+}
+
+impl LockChange {
+    pub const fn name(self) -> &'static str {
+        match self {
+            LockChange::Locked => "locked",
+            LockChange::Unlocked => "unlocked",
+        }
+    }
+}
+
+/// Movement totals the change in each amount field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Movement {
+    pub available: AmountDelta,
+    pub held: AmountDelta,
+    pub total: AmountDelta,
+}
+
+impl Movement {
+    /// The movement from `before` to `after`.
+    pub fn between(before: &SerializeClientState, after: &SerializeClientState) -> Self {
+        Movement {
+            available: AmountDelta::between(before.available, after.available),
+            held: AmountDelta::between(before.held, after.held),
+            total: AmountDelta::between(before.total, after.total),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.available.is_zero() && self.held.is_zero() && self.total.is_zero()
+    }
+}
+
+impl std::ops::AddAssign for Movement {
+    fn add_assign(&mut self, other: Self) {
+        self.available += other.available;
+        self.held += other.held;
+        self.total += other.total;
+    }
+}
+
+/// ClientChange describes how one client differs between the two sets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientChange {
+    pub client: ClientId,
+    pub presence: Presence,
+    pub movement: Movement,
+    /// Whether the account was locked or unlocked, if either; only a client in both sets can be.
+    pub lock: Option<LockChange>,
+}
+
+/// StateDiff is every difference between two sets of client state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    /// Each client which differs, in order of client.
+    pub changes: Vec<ClientChange>,
+    /// The number of clients in both sets which do not differ.
+    pub unchanged: usize,
+    /// The sum of the movement of every client.
+    pub movement: Movement,
+}
+
+impl StateDiff {
+    /// The number of clients with the given presence among the changes.
+    pub fn count(&self, presence: Presence) -> usize {
+        self.changes
+            .iter()
+            .filter(|change| change.presence == presence)
+            .count()
+    }
+
+    /// The clients whose accounts were locked or unlocked, as given.
+    pub fn lock_changes(&self, lock: LockChange) -> impl '_ + Iterator<Item = ClientId> {
+        self.changes
+            .iter()
+            .filter(move |change| change.lock == Some(lock))
+            .map(|change| change.client)
+    }
+
+    fn record(
+        &mut self,
+        presence: Presence,
+        before: &SerializeClientState,
+        after: &SerializeClientState,
+    ) {
+        let movement = Movement::between(before, after);
+        let lock = match (presence, before.locked, after.locked) {
+            (Presence::Both, false, true) => Some(LockChange::Locked),
+            (Presence::Both, true, false) => Some(LockChange::Unlocked),
+            _ => None,
+        };
+        if presence == Presence::Both && movement.is_zero() && lock.is_none() {
+            self.unchanged += 1;
+            return;
+        }
+        self.movement += movement;
+        self.changes.push(ClientChange {
+            client: after.client,
+            presence,
+            movement,
+            lock,
+        });
+    }
+}
+
+/// Diff two sets of client state, in which each client appears at most once.
+pub fn diff<B, A>(before: B, after: A) -> StateDiff
+where
+    B: IntoIterator<Item = SerializeClientState>,
+    A: IntoIterator<Item = SerializeClientState>,
+{
+    let mut before: BTreeMap<_, _> = before
+        .into_iter()
+        .map(|client| (client.client, client))
+        .collect();
+    let mut diff = StateDiff::default();
+    for after in after {
+        let (presence, before) = match before.remove(&after.client) {
+            Some(before) => (Presence::Both, before),
+            None => (Presence::Added, empty(after.client)),
+        };
+        diff.record(presence, &before, &after);
+    }
+    for before in before.into_values() {
+        let after = empty(before.client);
+        diff.record(Presence::Removed, &before, &after);
+    }
+    diff.changes.sort_by_key(|change| change.client);
+    diff
+}
+
+fn empty(client: ClientId) -> SerializeClientState {
+    ClientState::default().to_serialize(client)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadStateError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Csv(#[from] ReconcileError),
+    #[error(transparent)]
+    Binary(#[from] BinaryError),
+}
+
+/// Read a set of client state from a file: a checkpoint, client state written in the binary
+/// format, or client state written as CSV. The kind of file is recognized from its first bytes.
+pub fn read_state(path: &Path) -> Result<Vec<SerializeClientState>, ReadStateError> {
+    let mut input = BufReader::new(File::open(path)?);
+    let head = input.fill_buf()?;
+    if head.starts_with(checkpoint::MAGIC) {
+        let (_, clients) = Checkpoint::read_clients(path)?;
+        Ok(clients)
+    } else if head.starts_with(binary::MAGIC) {
+        // unlike a stream of events, a set of client state is of no use with records missing
+        Ok(binary::Reader::new(input)?.collect::<Result<_, _>>()?)
+    } else {
+        Ok(read_clients(input)?.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(client: u16, available: &str, held: &str, locked: bool) -> SerializeClientState {
+        ClientState {
+            available: available.parse().expect("test amounts are valid"),
+            held: held.parse().expect("test amounts are valid"),
+            locked,
+        }
+        .to_serialize(client.into())
+    }
+
+    #[test]
+    fn diffs_report_each_changed_client() {
+        let before = [
+            client(1, "5", "0", false),
+            client(2, "3", "1", false),
+            client(3, "1", "0", true),
+            client(4, "2", "0", false),
+        ];
+        let after = [
+            client(5, "0.5", "0", true),
+            client(4, "2", "0", false),
+            client(2, "3", "0", true),
+            client(1, "7.25", "0", false),
+        ];
+        let diff = diff(before, after);
+        assert_eq!(diff.unchanged, 1);
+        let clients: Vec<_> = diff.changes.iter().map(|c| u16::from(c.client)).collect();
+        assert_eq!(clients, [1, 2, 3, 5]);
+        assert_eq!(diff.count(Presence::Added), 1);
+        assert_eq!(diff.count(Presence::Removed), 1);
+
+        let first = &diff.changes[0];
+        assert_eq!(first.presence, Presence::Both);
+        assert_eq!(first.movement.available.to_string(), "+2.2500");
+        assert!(first.movement.held.is_zero());
+        assert_eq!(first.lock, None);
+
+        // client 5 is added already locked, and client 3 removed still locked; neither changed
+        let locked: Vec<_> = diff.lock_changes(LockChange::Locked).collect();
+        assert_eq!(locked, [2.into()]);
+        assert_eq!(diff.lock_changes(LockChange::Unlocked).count(), 0);
+        assert_eq!(diff.changes[2].lock, None);
+        assert_eq!(diff.changes[3].lock, None);
+
+        // +2.25, then -1 held, then -1 removed, then +0.5 added
+        assert_eq!(diff.movement.available.to_string(), "+1.7500");
+        assert_eq!(diff.movement.held.to_string(), "-1");
+        assert_eq!(diff.movement.total.to_string(), "+0.7500");
+    }
+
+    #[test]
+    fn identical_states_do_not_differ() {
+        let state = [client(1, "5", "1", false), client(2, "0", "0", true)];
+        let diff = diff(state.clone(), state);
+        assert!(diff.changes.is_empty());
+        assert_eq!(diff.unchanged, 2);
+        assert!(diff.movement.is_zero());
+    }
+}
@@ -0,0 +1,309 @@
+//! Following an input which is still being written.
+//!
+//! A `TailReader` turns a file which another process appends to into a stream which only ends
+//! when asked to stop. It hands out whole lines only, so a row which is half-written when it is
+//! read is held back until its newline arrives, and is never seen at all if reading stops first.
+//!
+//! A `Follower` parses that stream on a background thread, so that the engine can wait for records
+//! with a deadline and do other work, such as writing checkpoints, while the input is idle.
+//!
+//! Because the stream is split on line boundaries, quoted fields containing newlines are not
+//! supported. No field of an `Event` can legitimately contain one.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    input::{read_positioned_events, resume_positioned_events, ParseError, Position},
+    mapping::InputMapping,
+    primitives::Event,
+};
+
+/// The number of bytes requested from the underlying reader at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of parsed records which may wait for the engine before backpressure applies.
+const CHANNEL_BOUND: usize = 1024;
+
+/// FollowConfig controls how a growing input is watched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FollowConfig {
+    /// How long to wait before looking for more input, once all of it has been read.
+    pub poll_interval: Duration,
+}
+
+impl Default for FollowConfig {
+    fn default() -> Self {
+        FollowConfig {
+            poll_interval: Duration::from_millis(200),
+        }
+    }
+}
+
+/// TailReader reads whole lines from an input which is still growing.
+///
+/// Reaching the end of the input is not the end of the stream: the reader waits, and tries again.
+/// The stream ends, on a line boundary, once `stop` is set.
+pub struct TailReader<R> {
+    inner: R,
+    poll_interval: Duration,
+    stop: Arc<AtomicBool>,
+    buffer: Vec<u8>,
+    /// Bytes before this index have been handed out.
+    start: usize,
+    /// Bytes before this index form whole lines.
+    complete: usize,
+}
+
+impl<R> TailReader<R> {
+    pub fn new(inner: R, config: FollowConfig, stop: Arc<AtomicBool>) -> Self {
+        TailReader {
+            inner,
+            poll_interval: config.poll_interval,
+            stop,
+            buffer: Vec::new(),
+            start: 0,
+            complete: 0,
+        }
+    }
+
+    /// The trailing bytes which have been read, but which do not yet form a whole line.
+    pub fn partial(&self) -> &[u8] {
+        &self.buffer[self.complete..]
+    }
+}
+
+/// Seeking discards any partial line which has been read.
+impl<R: Seek> Seek for TailReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.buffer.clear();
+        self.start = 0;
+        self.complete = 0;
+        self.inner.seek(pos)
+    }
+}
+
+impl<R: Read> Read for TailReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.start < self.complete {
+                let n = (self.complete - self.start).min(out.len());
+                out[..n].copy_from_slice(&self.buffer[self.start..self.start + n]);
+                self.start += n;
+                return Ok(n);
+            }
+
+            // every whole line has been handed out, so this is a line boundary
+            self.buffer.drain(..self.start);
+            self.start = 0;
+            self.complete = 0;
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(0);
+            }
+
+            let len = self.buffer.len();
+            self.buffer.resize(len + CHUNK_SIZE, 0);
+            let read = self.inner.read(&mut self.buffer[len..]);
+            let n = match read {
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => 0,
+                Err(err) => {
+                    self.buffer.truncate(len);
+                    return Err(err);
+                }
+            };
+            self.buffer.truncate(len + n);
+            if n == 0 {
+                std::thread::sleep(self.poll_interval);
+            } else if let Some(newline) = self.buffer[len..].iter().rposition(|&b| b == b'\n') {
+                self.complete = len + newline + 1;
+            }
+        }
+    }
+}
+
+/// Follower parses records from a growing input on a background thread.
+///
+/// Dropping the follower asks the background thread to stop; it is not waited for.
+pub struct Follower {
+    records: Receiver<(Position, Result<Event, ParseError>)>,
+    stop: Arc<AtomicBool>,
+    finished: bool,
+}
+
+impl Follower {
+    /// Follow `input`, laid out as described by `mapping`, until `stop` is set, from the
+    /// beginning or from just after `start`.
+    ///
+    /// Records which were already parsed when `stop` is set are still delivered.
+    pub fn new<R>(
+        input: R,
+        mapping: InputMapping,
+        start: Option<Position>,
+        config: FollowConfig,
+        stop: Arc<AtomicBool>,
+    ) -> Self
+    where
+        R: 'static + Read + Seek + Send,
+    {
+        let (sender, records) = sync_channel(CHANNEL_BOUND);
+        let reader = TailReader::new(input, config, Arc::clone(&stop));
+        std::thread::spawn(move || {
+            let records: Box<dyn Iterator<Item = _>> = match start {
+                Some(start) => Box::new(resume_positioned_events(&mapping, reader, start)),
+                None => Box::new(read_positioned_events(&mapping, reader)),
+            };
+            for record in records {
+                if sender.send(record).is_err() {
+                    // the follower has been dropped
+                    return;
+                }
+            }
+        });
+        Follower {
+            records,
+            stop,
+            finished: false,
+        }
+    }
+
+    /// The records which arrive before `deadline`, each with the position just after it.
+    ///
+    /// The iterator ends at the deadline, or when the input has stopped.
+    pub fn until(
+        &mut self,
+        deadline: Instant,
+    ) -> impl '_ + Iterator<Item = (Position, Result<Event, ParseError>)> {
+        std::iter::from_fn(move || {
+            if self.finished {
+                return None;
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.records.recv_timeout(timeout) {
+                Ok(record) => Some(record),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.finished = true;
+                    None
+                }
+            }
+        })
+    }
+
+    /// Whether the input has stopped, and every record from it has been delivered.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// A reader which returns each chunk in turn, then sets `stop` once it runs dry.
+    struct Chunks {
+        chunks: VecDeque<&'static [u8]>,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.chunks.pop_front() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    Ok(chunk.len())
+                }
+                None => {
+                    self.stop.store(true, Ordering::Relaxed);
+                    Ok(0)
+                }
+            }
+        }
+    }
+
+    fn config() -> FollowConfig {
+        FollowConfig {
+            poll_interval: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn only_whole_lines_are_read() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let chunks = Chunks {
+            chunks: VecDeque::from([
+                &b"type,client,tx,amount\ndeposit,1,1,"[..],
+                b"",
+                b"1.0\ndeposit,1,2,2.0\ndep",
+            ]),
+            stop: Arc::clone(&stop),
+        };
+        let mut reader = TailReader::new(chunks, config(), stop);
+
+        let mut read = String::new();
+        reader
+            .read_to_string(&mut read)
+            .expect("reading from memory succeeds");
+        assert_eq!(
+            read,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\n"
+        );
+        assert_eq!(reader.partial(), b"dep");
+    }
+
+    #[test]
+    fn records_arrive_as_the_file_grows() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("follow-test-{}.csv", std::process::id()));
+        let mut file = std::fs::File::create(&path).expect("temp dir is writable");
+        file.write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,")
+            .expect("temp dir is writable");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let input = std::fs::File::open(&path).expect("file was just created");
+        let mut follower = Follower::new(
+            input,
+            InputMapping::default(),
+            None,
+            config(),
+            Arc::clone(&stop),
+        );
+
+        let wait = || Instant::now() + Duration::from_millis(200);
+        let first: Vec<_> = follower.until(wait()).collect();
+        assert_eq!(first.len(), 1);
+        assert!(!follower.is_finished());
+
+        file.write_all(b"2.0\n").expect("temp dir is writable");
+        let second: Vec<_> = follower.until(wait()).collect();
+        assert_eq!(second.len(), 1);
+        let (position, record) = &second[0];
+        assert_eq!(record.as_ref().expect("record is valid").tx, 2.into());
+        assert_eq!(position.record, 2);
+
+        stop.store(true, Ordering::Relaxed);
+        let rest: Vec<_> = follower.until(wait()).collect();
+        assert!(rest.is_empty());
+        assert!(follower.is_finished());
+
+        std::fs::remove_file(&path).expect("file was just created");
+    }
+}
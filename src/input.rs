@@ -0,0 +1,831 @@
+//! Reading events from CSV, NDJSON, or binary input, without giving up on the first malformed row.
+//!
+//! Inputs compressed with gzip or zstd are decompressed as they are read.
+
+use std::{
+    fmt,
+    io::{self, BufRead, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    binary::{self, BinaryError},
+    mapping::InputMapping,
+    primitives::{Amount, ClientId, Event, EventType, TransactionId},
+};
+
+/// An InputSource is somewhere events can be read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputSource {
+    Stdin,
+    File(PathBuf),
+}
+
+impl InputSource {
+    /// Open this source for reading, decompressing it if it is compressed.
+    pub fn open(&self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            InputSource::Stdin => decompress(io::stdin()),
+            InputSource::File(path) => open_decompressed(path),
+        }
+    }
+}
+
+/// The compression applied to an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// The most leading bytes needed to recognize any compression.
+const MAGIC_LEN: usize = 4;
+
+impl Compression {
+    /// The compression implied by the extension of `path`: `.gz` or `.zst`.
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Compression::Gzip),
+            "zst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The compression indicated by the leading bytes of an input.
+    pub fn from_magic(bytes: &[u8]) -> Self {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// The compression of the file at `path`, by its extension, or failing that, its leading bytes.
+    pub fn of_file(path: &Path) -> io::Result<Self> {
+        if let Some(compression) = Compression::from_extension(path) {
+            return Ok(compression);
+        }
+        let mut magic = Vec::with_capacity(MAGIC_LEN);
+        std::fs::File::open(path)?
+            .take(MAGIC_LEN as u64)
+            .read_to_end(&mut magic)?;
+        Ok(Compression::from_magic(&magic))
+    }
+
+    /// Wrap `input` in a streaming decoder for this compression.
+    ///
+    /// Concatenated gzip members are read as one stream, as `gunzip` does.
+    pub fn decoder<R>(self, input: R) -> io::Result<Box<dyn Read + Send>>
+    where
+        R: 'static + Read + Send,
+    {
+        Ok(match self {
+            Compression::None => Box::new(input),
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(input)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(input)?),
+        })
+    }
+}
+
+/// Decompress `input`, recognizing its compression by its leading bytes.
+///
+/// The leading bytes are replayed ahead of the rest of the input, so nothing is lost when the
+/// input turns out not to be compressed.
+pub fn decompress<R>(mut input: R) -> io::Result<Box<dyn Read + Send>>
+where
+    R: 'static + Read + Send,
+{
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    (&mut input)
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut magic)?;
+    Compression::from_magic(&magic).decoder(io::Cursor::new(magic).chain(input))
+}
+
+/// Open the file at `path`, decompressing it according to its extension, or failing that, its
+/// leading bytes.
+pub fn open_decompressed(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    let file = std::fs::File::open(path)?;
+    match Compression::from_extension(path) {
+        Some(compression) => compression.decoder(file),
+        None => decompress(file),
+    }
+}
+
+impl fmt::Display for InputSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputSource::Stdin => write!(f, "<stdin>"),
+            InputSource::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Expand input arguments into an ordered list of sources.
+///
+/// Arguments are expanded in the order given, according to these rules:
+///
+/// - `-` is standard input.
+/// - A directory expands to the files directly within it, sorted by name.
+/// - An argument containing any of `*`, `?`, or `[` is a glob pattern, and expands to the files
+///   it matches, sorted by path. It is an error for a pattern to match no files.
+/// - Any other argument is the path of a file.
+pub fn expand_inputs<S: AsRef<str>>(args: &[S]) -> io::Result<Vec<InputSource>> {
+    let mut sources = Vec::new();
+    for arg in args {
+        let arg = arg.as_ref();
+        let path = Path::new(arg);
+        if arg == "-" {
+            sources.push(InputSource::Stdin);
+        } else if path.is_dir() {
+            let mut files = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            files.retain(|file| file.is_file());
+            files.sort();
+            sources.extend(files.into_iter().map(InputSource::File));
+        } else if arg.contains(['*', '?', '[']) {
+            let pattern =
+                glob::glob(arg).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let mut files = pattern
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(io::Error::from)?;
+            files.retain(|file| file.is_file());
+            if files.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no files match `{arg}`"),
+                ));
+            }
+            files.sort();
+            sources.extend(files.into_iter().map(InputSource::File));
+        } else {
+            sources.push(InputSource::File(path.to_owned()));
+        }
+    }
+    Ok(sources)
+}
+
+/// A ParseError describes a row of input which could not be understood as an `Event`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "{}line {line}: could not parse record `{text}`: {reason}",
+    .origin.as_ref().map(|origin| format!("{origin}, ")).unwrap_or_default()
+)]
+pub struct ParseError {
+    /// The name of the input containing the record, when there may be more than one.
+    pub origin: Option<String>,
+    /// The line of input on which the record begins, counting from 1.
+    ///
+    /// Binary input has no lines; this is the number of the record instead.
+    pub line: u64,
+    /// The raw text of the record, as it appears in the input. Binary input has no text.
+    pub text: String,
+    /// Why the record could not be parsed.
+    pub reason: String,
+}
+
+impl ParseError {
+    fn new(line: u64, record: Option<RawRecord>, err: Option<&csv::Error>) -> Self {
+        ParseError {
+            origin: None,
+            line,
+            text: record
+                .map(|record| String::from_utf8_lossy(record.text).into_owned())
+                .unwrap_or_default(),
+            reason: err.map(describe).unwrap_or_default(),
+        }
+    }
+
+    /// Attribute this error to the named input.
+    pub fn with_origin(self, origin: impl Into<String>) -> Self {
+        ParseError {
+            origin: Some(origin.into()),
+            ..self
+        }
+    }
+}
+
+impl ParseError {
+    /// Construct a parse error from an error produced by a reader which began at `first_line`.
+    pub(crate) fn from_csv(first_line: u64, err: &csv::Error) -> Self {
+        let line = err.position().map_or(1, |position| position.line());
+        ParseError::new(first_line + line.saturating_sub(1), None, Some(err))
+    }
+}
+
+/// Describe a CSV error without the position information which `csv::Error`'s `Display` includes,
+/// because positions within a chunk of input are not positions within the whole input.
+fn describe(err: &csv::Error) -> String {
+    match err.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        csv::ErrorKind::Io(err) => format!("I/O error: {err}"),
+        csv::ErrorKind::Utf8 { err, .. } => format!("invalid UTF-8: {err}"),
+        csv::ErrorKind::UnequalLengths {
+            expected_len, len, ..
+        } => format!("expected {expected_len} fields but found {len}"),
+        _ => err.to_string(),
+    }
+}
+
+/// Deserialize a single record into an `Event`.
+///
+/// `raw` is where the record appears in the input. Readers should be flexible, so that records
+/// with the wrong number of fields reach this function and can be reported along with their text.
+pub fn parse_record(
+    headers: &csv::ByteRecord,
+    record: &csv::ByteRecord,
+    raw: RawRecord,
+) -> Result<Event, ParseError> {
+    if record.len() != headers.len() {
+        return Err(ParseError {
+            reason: format!(
+                "expected {} fields but found {}",
+                headers.len(),
+                record.len()
+            ),
+            ..ParseError::new(raw.line, Some(raw), None)
+        });
+    }
+    record
+        .deserialize(Some(headers))
+        .map(CsvEvent::into_event)
+        .map_err(|err| ParseError::new(raw.line, Some(raw), Some(&err)))
+}
+
+/// RawRecord is a record as it appears in the input: the line it begins on, and its text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawRecord<'a> {
+    pub line: u64,
+    pub text: &'a [u8],
+}
+
+impl<'a> RawRecord<'a> {
+    /// Find the record which `reader` has just read into `record`, given the bytes of the input
+    /// from where that record's position begins, counting lines from `first_line`.
+    ///
+    /// The position which `csv` records for a record is where it began looking for that record,
+    /// which precedes any comments or blank lines before it. Those lines are skipped here, so that
+    /// the record's text is its own, including any newlines quoted within it.
+    pub(crate) fn locate<R: Read>(
+        reader: &csv::Reader<R>,
+        record: &csv::ByteRecord,
+        input: &'a [u8],
+        first_line: u64,
+    ) -> Self {
+        let start = record
+            .position()
+            .expect("records read by csv have a position");
+        let len = reader.position().byte() - start.byte();
+        let mut text = &input[..len as usize];
+        let mut line = first_line + start.line() - 1;
+        while let Some(end) = text.iter().position(|&byte| byte == b'\n') {
+            if !matches!(text[..end], [] | [b'\r'] | [b'#', ..]) {
+                break;
+            }
+            text = &text[end + 1..];
+            line += 1;
+        }
+        let text = text.strip_suffix(b"\n").unwrap_or(text);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        RawRecord { line, text }
+    }
+}
+
+/// An event as it appears in CSV.
+///
+/// The amount is parsed from the text of its field. Left to itself, `csv` would infer a float,
+/// which cannot hold amounts of more than 15 significant digits exactly.
+#[derive(Deserialize)]
+struct CsvEvent {
+    #[serde(rename = "type")]
+    event_type: EventType,
+    client: ClientId,
+    tx: TransactionId,
+    #[serde(default, deserialize_with = "amount_text")]
+    amount: Amount,
+}
+
+impl CsvEvent {
+    fn into_event(self) -> Event {
+        let CsvEvent {
+            event_type,
+            client,
+            tx,
+            amount,
+        } = self;
+        Event {
+            event_type,
+            client,
+            tx,
+            amount,
+        }
+    }
+}
+
+/// Deserialize an amount from the text of a CSV field, exactly; an empty field is zero.
+pub(crate) fn amount_text<'de, D>(de: D) -> Result<Amount, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let text = <&str>::deserialize(de)?;
+    if text.is_empty() {
+        return Ok(Amount::ZERO);
+    }
+    match text.parse() {
+        Ok(amount) => Ok(amount),
+        // other notations, such as exponents, are still read as floats
+        Err(err) => match text.parse::<f64>() {
+            Ok(value) => Amount::try_from(value).map_err(serde::de::Error::custom),
+            Err(_) => Err(serde::de::Error::custom(err)),
+        },
+    }
+}
+
+/// Terminated ensures that its input ends with a newline, so that the position after every record
+/// is at the start of a line.
+pub(crate) struct Terminated<R> {
+    inner: R,
+    last: Option<u8>,
+    done: bool,
+}
+
+impl<R> Terminated<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Terminated {
+            inner,
+            last: None,
+            done: false,
+        }
+    }
+}
+
+/// Seeking forgets what has been read, so a newline is only added if more is read afterwards.
+impl<R: Seek> Seek for Terminated<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.last = None;
+        self.done = false;
+        self.inner.seek(pos)
+    }
+}
+
+impl<R: Read> Read for Terminated<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.last = Some(buf[n - 1]);
+            return Ok(n);
+        }
+        self.done = true;
+        match self.last {
+            Some(b'\n') | None => Ok(0),
+            Some(_) => {
+                buf[0] = b'\n';
+                Ok(1)
+            }
+        }
+    }
+}
+
+/// Recorded keeps the bytes read from its input, so that the text of each record can be found
+/// once `csv` has parsed it.
+pub(crate) struct Recorded<R> {
+    inner: R,
+    /// The offset in the input of the first byte kept.
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+impl<R> Recorded<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Recorded {
+            inner,
+            start: 0,
+            bytes: Vec::new(),
+        }
+    }
+
+    /// Forget the bytes before `offset`.
+    fn discard_before(&mut self, offset: u64) {
+        let len = (offset.saturating_sub(self.start) as usize).min(self.bytes.len());
+        self.bytes.drain(..len);
+        self.start += len as u64;
+    }
+
+    /// The bytes kept, beginning at the offset last passed to `discard_before`.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<R: Seek> Seek for Recorded<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let offset = self.inner.seek(pos)?;
+        self.start = offset;
+        self.bytes.clear();
+        Ok(offset)
+    }
+}
+
+impl<R: Read> Read for Recorded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Read events from `input`, laid out as described by `mapping`, yielding a `ParseError` for each
+/// malformed row.
+///
+/// Reading stops at the first I/O error.
+pub fn read_events<R: Read>(
+    mapping: &InputMapping,
+    input: R,
+) -> impl Iterator<Item = Result<Event, ParseError>> {
+    read_positioned_events(mapping, input).map(|(_, record)| record)
+}
+
+/// The format in which events are read.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Comma-separated values with a header row.
+    Csv,
+    /// One JSON object per line, with the same fields as the CSV columns.
+    Ndjson,
+    /// The compact encoding of the `binary` module.
+    Binary,
+}
+
+/// Read events from `input`, one JSON object per line, yielding a `ParseError` for each line which
+/// is not an event.
+///
+/// Blank lines are skipped. An amount may be given as a number or as a string; events which carry
+/// no amount may omit it, or give it as null. Reading stops at the first I/O error.
+pub fn read_ndjson_events<R: Read>(input: R) -> impl Iterator<Item = Result<Event, ParseError>> {
+    let mut input = io::BufReader::new(input);
+    let mut buf = Vec::new();
+    let mut line = 0;
+    let mut done = false;
+    std::iter::from_fn(move || loop {
+        if done {
+            return None;
+        }
+        buf.clear();
+        line += 1;
+        match input.read_until(b'\n', &mut buf) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(err) => {
+                done = true;
+                return Some(Err(ParseError {
+                    reason: format!("I/O error: {err}"),
+                    ..ParseError::new(line, None, None)
+                }));
+            }
+        }
+        let text = buf.trim_ascii_end();
+        if text.is_empty() {
+            continue;
+        }
+        return Some(serde_json::from_slice(text).map_err(|err| ParseError {
+            text: String::from_utf8_lossy(text.trim_ascii_start()).into_owned(),
+            reason: describe_json(&err),
+            ..ParseError::new(line, None, None)
+        }));
+    })
+}
+
+/// Read events from a binary stream, yielding a `ParseError` for each record which is not an event.
+///
+/// The header of the stream is read first; an error is returned if it is not a stream of events.
+/// Reading stops at the first I/O error or truncated record.
+pub fn read_binary_events<R: Read>(
+    input: R,
+) -> Result<impl Iterator<Item = Result<Event, ParseError>>, BinaryError> {
+    let reader = binary::Reader::<_, Event>::new(input)?;
+    Ok(reader.zip(1..).map(|(result, record)| {
+        result.map_err(|err| ParseError {
+            reason: err.to_string(),
+            ..ParseError::new(record, None, None)
+        })
+    }))
+}
+
+/// Describe a JSON error by its column alone, because each line is parsed separately.
+fn describe_json(err: &serde_json::Error) -> String {
+    let message = err.to_string();
+    let position = format!(" at line {} column {}", err.line(), err.column());
+    match message.strip_suffix(&position) {
+        Some(message) => format!("{message} at column {}", err.column()),
+        None => message,
+    }
+}
+
+/// Position marks a point in an input just after some record, from which reading can resume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// The offset in bytes from the start of the input.
+    pub byte: u64,
+    /// The line on which the next record may begin, counting from 1.
+    pub line: u64,
+    /// The number of records read, whether or not they parsed; the header row is not counted.
+    pub record: u64,
+}
+
+impl Position {
+    /// The start of an input, before its header row has been read.
+    pub const START: Position = Position {
+        byte: 0,
+        line: 1,
+        record: 0,
+    };
+}
+
+impl From<Position> for csv::Position {
+    fn from(position: Position) -> Self {
+        let mut csv_position = csv::Position::new();
+        csv_position
+            .set_byte(position.byte)
+            .set_line(position.line)
+            .set_record(position.record);
+        csv_position
+    }
+}
+
+/// Read events from `input` as `read_events` does, pairing each with the position just after it.
+pub fn read_positioned_events<R: Read>(
+    mapping: &InputMapping,
+    input: R,
+) -> impl Iterator<Item = (Position, Result<Event, ParseError>)> {
+    let reader = mapping
+        .reader_builder()
+        .from_reader(Recorded::new(Terminated::new(input)));
+    positioned(reader, mapping, Position::START, None)
+}
+
+/// Read events from `input` as `read_positioned_events` does, resuming just after `start`.
+///
+/// The header row is read from the beginning of the input, then the input is seeked to `start`,
+/// which must be a position produced by reading the same input.
+pub fn resume_positioned_events<R: Read + Seek>(
+    mapping: &InputMapping,
+    input: R,
+    start: Position,
+) -> impl Iterator<Item = (Position, Result<Event, ParseError>)> {
+    let mut reader = mapping
+        .reader_builder()
+        .from_reader(Recorded::new(Terminated::new(input)));
+    let seek_error = reader.seek(start.into()).err();
+    positioned(reader, mapping, start, seek_error)
+}
+
+fn positioned<R: Read>(
+    mut reader: csv::Reader<Recorded<Terminated<R>>>,
+    mapping: &InputMapping,
+    start: Position,
+    seek_error: Option<csv::Error>,
+) -> impl Iterator<Item = (Position, Result<Event, ParseError>)> {
+    let headers = match (seek_error, mapping.header_record()) {
+        (Some(err), _) => Err(err),
+        (None, Some(headers)) => Ok(headers),
+        (None, None) => reader.byte_headers().cloned(),
+    };
+    let (mut done, headers, header_error) = match headers {
+        Ok(headers) => (false, mapping.compile(&headers), None),
+        Err(err) => (
+            true,
+            mapping.compile(&csv::ByteRecord::new()),
+            Some((start, Err(ParseError::from_csv(1, &err)))),
+        ),
+    };
+
+    let mut position = start;
+    let mut record = csv::ByteRecord::new();
+    let records = std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let parsed = match reader.read_byte_record(&mut record) {
+            Ok(true) => {
+                let start = record
+                    .position()
+                    .expect("records read by csv have a position");
+                reader.get_mut().discard_before(start.byte());
+                let raw = RawRecord::locate(&reader, &record, reader.get_ref().bytes(), 1);
+                headers.parse(&record, raw)
+            }
+            Ok(false) => return None,
+            Err(err) => {
+                done = matches!(err.kind(), csv::ErrorKind::Io(_));
+                Err(ParseError::from_csv(1, &err))
+            }
+        };
+        position = Position {
+            byte: reader.position().byte(),
+            line: reader.position().line(),
+            record: position.record + 1,
+        };
+        Some((position, parsed))
+    });
+
+    header_error.into_iter().chain(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inputs_expand_in_a_deterministic_order() {
+        let dir = std::env::temp_dir().join(format!("transacty-inputs-{}", std::process::id()));
+        let nested = dir.join("nested");
+        std::fs::create_dir_all(&nested).expect("temp dir is writable");
+        for name in ["b.csv", "a.csv", "c.txt", "nested/d.csv"] {
+            std::fs::write(dir.join(name), "").expect("temp dir is writable");
+        }
+        let arg = |name: &str| dir.join(name).display().to_string();
+
+        let sources = expand_inputs(&[arg(""), "-".into(), arg("*.csv"), arg("c.txt")]);
+        std::fs::remove_dir_all(&dir).expect("temp dir is writable");
+
+        let file = |name: &str| InputSource::File(dir.join(name));
+        assert_eq!(
+            sources.expect("all inputs exist"),
+            [
+                file("a.csv"),
+                file("b.csv"),
+                file("c.txt"),
+                InputSource::Stdin,
+                file("a.csv"),
+                file("b.csv"),
+                file("c.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn compressed_inputs_read_like_plain_ones() {
+        use std::io::Write;
+
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,0.5\n";
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(data.as_bytes())
+            .expect("writing to a vec succeeds");
+        let gzip = gzip.finish().expect("writing to a vec succeeds");
+        let zstd = zstd::encode_all(data.as_bytes(), 0).expect("writing to a vec succeeds");
+
+        let dir = std::env::temp_dir().join(format!("transacty-compressed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("temp dir is writable");
+        let files = [
+            ("plain.csv", data.as_bytes()),
+            ("events.csv.gz", &gzip),
+            ("events.csv.zst", &zstd),
+            // misnamed, so recognized by its leading bytes
+            ("gzip.csv", &gzip),
+        ];
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).expect("temp dir is writable");
+        }
+
+        let expect: Vec<_> = read_events(&InputMapping::default(), data.as_bytes()).collect();
+        for (name, _) in files {
+            let path = dir.join(name);
+            let input = InputSource::File(path.clone())
+                .open()
+                .expect("file was just written");
+            let events: Vec<_> = read_events(&InputMapping::default(), input).collect();
+            assert_eq!(events, expect, "{name}");
+        }
+        assert_eq!(
+            Compression::of_file(&dir.join("gzip.csv")).expect("file was just written"),
+            Compression::Gzip
+        );
+        for stream in [data.as_bytes().to_vec(), zstd] {
+            let input = decompress(io::Cursor::new(stream)).expect("reading from memory succeeds");
+            let events: Vec<_> = read_events(&InputMapping::default(), input).collect();
+            assert_eq!(events, expect);
+        }
+        std::fs::remove_dir_all(&dir).expect("dir was just created");
+    }
+
+    #[test]
+    fn ndjson_lines_are_read_with_their_errors() {
+        let data = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}
+{"type": "deposit", "client": 1, "tx": 2, "amount": "2.0000"}
+
+{"type": "dispute", "client": 1, "tx": 1}
+{"type": "resolve", "client": 1, "tx": 1, "amount": null}
+{"type": "refund", "client": 1, "tx": 3, "amount": "1.0"}
+{"type": "withdrawal", "client": 1, "tx": 4
+{"type": "withdrawal", "client": 1, "tx": 5, "amount": -1}
+  {"type": "withdrawal", "client": 1, "tx": 6, "amount": 3}"#;
+        let results: Vec<_> = read_ndjson_events(data.as_bytes()).collect();
+
+        assert_eq!(results.len(), 8);
+        let event = |index: usize| results[index].as_ref().expect("event is valid");
+        assert_eq!(event(0).amount, "1.5".parse().expect("valid amount"));
+        assert_eq!(event(1).amount, "2".parse().expect("valid amount"));
+        assert!(event(2).amount.is_zero());
+        assert!(event(3).amount.is_zero());
+        assert_eq!(event(7).tx, 6.into());
+
+        let errors: Vec<_> = results.iter().filter_map(|r| r.as_ref().err()).collect();
+        let lines: Vec<_> = errors.iter().map(|err| err.line).collect();
+        assert_eq!(lines, [6, 7, 8]);
+        assert!(errors[0].reason.contains("refund"));
+        assert!(errors[0].reason.contains("column"));
+        assert!(!errors[0].reason.contains("line"));
+        assert_eq!(
+            errors[1].text,
+            r#"{"type": "withdrawal", "client": 1, "tx": 4"#
+        );
+    }
+
+    #[test]
+    fn malformed_rows_are_reported_with_their_line() {
+        let data = "\
+# a comment
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, -1.0
+# another comment
+refund, 1, 3, 1.0
+withdrawal, 1, 4
+withdrawal, x, 5, 1.0
+deposit, 1, 6, 2.0";
+        let results: Vec<_> = read_events(&InputMapping::default(), data.as_bytes()).collect();
+
+        assert_eq!(results.len(), 6);
+        assert!(results[0].is_ok());
+        assert!(results[5].is_ok());
+
+        let errors: Vec<_> = results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .collect();
+        let lines: Vec<_> = errors.iter().map(|err| err.line).collect();
+        assert_eq!(lines, [4, 6, 7, 8]);
+        assert_eq!(errors[0].text, "deposit, 1, 2, -1.0");
+        assert_eq!(errors[1].text, "refund, 1, 3, 1.0");
+        assert_eq!(errors[2].text, "withdrawal, 1, 4");
+        assert_eq!(errors[2].reason, "expected 4 fields but found 3");
+    }
+
+    #[test]
+    fn malformed_rows_keep_their_raw_text() {
+        let data = "type,client,tx,amount\r
+deposit,1,1,\"1.0\"\r
+  \"refund\" ,  1 , 2,1.0  \r
+deposit,\"1\",3,\"multi
+line\"\r
+\r
+# a comment\r
+withdrawal,\"x\",4,1.0";
+        let results: Vec<_> = read_events(&InputMapping::default(), data.as_bytes()).collect();
+
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_ok());
+        let errors: Vec<_> = results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .collect();
+        let lines: Vec<_> = errors.iter().map(|err| err.line).collect();
+        assert_eq!(lines, [3, 4, 8]);
+        assert_eq!(errors[0].text, "  \"refund\" ,  1 , 2,1.0  ");
+        assert_eq!(errors[1].text, "deposit,\"1\",3,\"multi\nline\"");
+        assert_eq!(errors[2].text, "withdrawal,\"x\",4,1.0");
+    }
+
+    #[test]
+    fn reading_resumes_from_any_position() {
+        let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+# a comment
+refund, 1, 2, 1.0
+withdrawal, 1, 3, 0.5
+deposit, 1, 4, 2.0";
+        let mapping = InputMapping::default();
+        let all: Vec<_> = read_positioned_events(&mapping, data.as_bytes()).collect();
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[3].0.record, 4);
+
+        for (idx, (position, _)) in all.iter().enumerate() {
+            let input = io::Cursor::new(data.as_bytes());
+            let resumed: Vec<_> = resume_positioned_events(&mapping, input, *position).collect();
+            assert_eq!(resumed, all[idx + 1..]);
+        }
+    }
+}
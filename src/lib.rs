@@ -1,9 +1,170 @@
+pub mod binary;
+pub mod checkpoint;
+#[cfg(feature = "parquet")]
+pub mod columnar;
+pub mod diff;
+pub mod follow;
+pub mod input;
+pub mod lint;
+pub mod mapping;
+pub mod output;
+pub mod pipeline;
 pub mod primitives;
+pub mod reconcile;
+pub mod sharded;
 pub mod state;
+pub mod statement;
+pub mod summary;
 
-use primitives::{ClientId, Event, TransactionId};
+use input::ParseError;
+use primitives::{ClientId, Event, EventType, TransactionId};
+use serde::Serialize;
 use state::StateManager;
 
+/// Construct a CSV reader configured for event input: fields are trimmed, and `#` begins a comment.
+///
+/// The reader is flexible about the number of fields per record, so that `input::parse_record`
+/// can report malformed records along with their text.
+pub fn csv_reader_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .flexible(true);
+    builder
+}
+
+/// ProcessCounts tallies the outcome of feeding records into a state manager.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessCounts {
+    /// Records read, whether or not they parsed.
+    pub records: usize,
+    /// Errors reported, including parse errors and errors deferred from earlier records.
+    pub errors: usize,
+    /// Errors reported, by reason.
+    pub reasons: ReasonCounts,
+    /// Records which parsed, by event type.
+    pub event_types: EventTypeCounts,
+    /// Whether processing stopped early because errors could no longer be sent.
+    pub stopped: bool,
+}
+
+impl ProcessCounts {
+    /// Check that processing was not stopped early, so that the state reflects every record.
+    pub fn check_complete(&self) -> Result<(), StoppedEarly> {
+        if self.stopped {
+            Err(StoppedEarly)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// StoppedEarly is returned for processing which stopped before the end of its input, because
+/// errors could no longer be sent.
+#[derive(Debug, thiserror::Error)]
+#[error("processing stopped early because errors could not be reported")]
+pub struct StoppedEarly;
+
+impl std::ops::AddAssign for ProcessCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.records += other.records;
+        self.errors += other.errors;
+        self.reasons += other.reasons;
+        self.event_types += other.event_types;
+        self.stopped |= other.stopped;
+    }
+}
+
+/// ReasonCounts tallies errors by their reason.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReasonCounts([usize; Reason::ALL.len()]);
+
+impl ReasonCounts {
+    /// Iterate over the count for every reason, in order of their codes.
+    pub fn iter(&self) -> impl '_ + Iterator<Item = (Reason, usize)> {
+        Reason::ALL.into_iter().zip(self.0.iter().copied())
+    }
+}
+
+impl std::ops::Index<Reason> for ReasonCounts {
+    type Output = usize;
+
+    fn index(&self, reason: Reason) -> &usize {
+        &self.0[reason.code() as usize - 1]
+    }
+}
+
+impl std::ops::IndexMut<Reason> for ReasonCounts {
+    fn index_mut(&mut self, reason: Reason) -> &mut usize {
+        &mut self.0[reason.code() as usize - 1]
+    }
+}
+
+impl std::ops::AddAssign for ReasonCounts {
+    fn add_assign(&mut self, other: Self) {
+        for (count, other) in self.0.iter_mut().zip(other.0) {
+            *count += other;
+        }
+    }
+}
+
+/// Reasons are serialized by name.
+impl Serialize for ReasonCounts {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(self.iter().map(|(reason, count)| (reason.name(), count)))
+    }
+}
+
+/// EventTypeCounts tallies records by their event type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EventTypeCounts([usize; EventType::ALL.len()]);
+
+impl EventTypeCounts {
+    /// Iterate over the count for every event type.
+    pub fn iter(&self) -> impl '_ + Iterator<Item = (EventType, usize)> {
+        EventType::ALL.into_iter().zip(self.0.iter().copied())
+    }
+}
+
+impl std::ops::Index<EventType> for EventTypeCounts {
+    type Output = usize;
+
+    fn index(&self, event_type: EventType) -> &usize {
+        &self.0[event_type as usize]
+    }
+}
+
+impl std::ops::IndexMut<EventType> for EventTypeCounts {
+    fn index_mut(&mut self, event_type: EventType) -> &mut usize {
+        &mut self.0[event_type as usize]
+    }
+}
+
+impl std::ops::AddAssign for EventTypeCounts {
+    fn add_assign(&mut self, other: Self) {
+        for (count, other) in self.0.iter_mut().zip(other.0) {
+            *count += other;
+        }
+    }
+}
+
+/// Event types are serialized by name.
+impl Serialize for EventTypeCounts {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_map(
+            self.iter()
+                .map(|(event_type, count)| (event_type.name(), count)),
+        )
+    }
+}
+
 /// Process a stream of events, updating global state appropriately.
 ///
 /// If `errors` is not `None`, errors will be sent along that channel.
@@ -13,26 +174,123 @@ pub fn process_events<State, I>(
     state: &mut State,
     events: I,
     errors: Option<std::sync::mpsc::SyncSender<EventError<<State as StateManager>::Err>>>,
-) where
+) -> ProcessCounts
+where
     State: StateManager,
     I: IntoIterator<Item = Event>,
 {
-    for event in events.into_iter() {
-        if let Err(err) = state.handle_event(event) {
-            if let Some(errors) = &errors {
-                if errors.send(err).is_err() {
-                    eprintln!("event processing terminated early due to send error");
-                    break;
-                }
+    process_records(state, events.into_iter().map(Ok), errors)
+}
+
+/// Process a stream of parsed records, updating global state appropriately.
+///
+/// Records which failed to parse are reported as `EventError::Parse`, and otherwise skipped.
+/// See `process_events`.
+pub fn process_records<State, I>(
+    state: &mut State,
+    records: I,
+    errors: Option<std::sync::mpsc::SyncSender<EventError<<State as StateManager>::Err>>>,
+) -> ProcessCounts
+where
+    State: StateManager,
+    I: IntoIterator<Item = Result<Event, ParseError>>,
+{
+    let mut counts = feed_records(state, records, errors.as_ref());
+    if !counts.stopped {
+        counts += finish_processing(state, errors.as_ref());
+    }
+    counts
+}
+
+/// Process a stream of parsed records as `process_records` does, summarizing the run.
+pub fn process_records_summarized<State, I>(
+    state: &mut State,
+    records: I,
+    errors: Option<std::sync::mpsc::SyncSender<EventError<<State as StateManager>::Err>>>,
+) -> summary::Summary
+where
+    State: StateManager,
+    I: IntoIterator<Item = Result<Event, ParseError>>,
+{
+    let started = std::time::Instant::now();
+    let counts = process_records(state, records, errors);
+    summary::Summary::new(&counts, state, started.elapsed())
+}
+
+/// Send each error along `errors`, if present, counting them.
+///
+/// Sets `counts.stopped` if an error could not be sent.
+pub(crate) fn report<E>(
+    errors: Option<&std::sync::mpsc::SyncSender<EventError<E>>>,
+    reported: impl IntoIterator<Item = EventError<E>>,
+    counts: &mut ProcessCounts,
+) {
+    for err in reported {
+        counts.errors += 1;
+        counts.reasons[err.reason()] += 1;
+        if let Some(errors) = errors {
+            if errors.send(err).is_err() {
+                eprintln!("event processing terminated early due to send error");
+                counts.stopped = true;
+                return;
+            }
+        }
+    }
+}
+
+/// Feed a stream of parsed records into a state manager, without signalling the end of the stream.
+///
+/// This allows several inputs to be processed into the same state in sequence; call
+/// `finish_processing` once they are exhausted. See `process_records`.
+pub fn feed_records<State, I>(
+    state: &mut State,
+    records: I,
+    errors: Option<&std::sync::mpsc::SyncSender<EventError<<State as StateManager>::Err>>>,
+) -> ProcessCounts
+where
+    State: StateManager,
+    I: IntoIterator<Item = Result<Event, ParseError>>,
+{
+    let mut counts = ProcessCounts::default();
+    for record in records.into_iter() {
+        counts.records += 1;
+        let result = match record {
+            Ok(event) => {
+                counts.event_types[event.event_type] += 1;
+                state.handle_event(event)
             }
+            Err(err) => Err(EventError::Parse(err)),
+        };
+        report(
+            errors,
+            result.err().into_iter().chain(state.take_deferred_errors()),
+            &mut counts,
+        );
+        if counts.stopped {
+            break;
         }
     }
+    counts
 }
 
-#[derive(Debug, thiserror::Error)]
+/// Signal the end of the stream to a state manager, reporting any errors which that produces.
+pub fn finish_processing<State>(
+    state: &mut State,
+    errors: Option<&std::sync::mpsc::SyncSender<EventError<<State as StateManager>::Err>>>,
+) -> ProcessCounts
+where
+    State: StateManager,
+{
+    let mut counts = ProcessCounts::default();
+    state.finish();
+    report(errors, state.take_deferred_errors(), &mut counts);
+    counts
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum EventError<E> {
-    #[error("transaction {0} already exists; IDs may not be duplicated")]
-    DuplicateTransactionId(TransactionId),
+    #[error("transaction {1} already exists; IDs may not be duplicated")]
+    DuplicateTransactionId(ClientId, TransactionId),
     #[error("client {0} has insufficient funds to withdraw as requested by transaction {1}")]
     InsufficientFunds(ClientId, TransactionId),
     #[error("client {0} cannot withdraw per transaction {1} because their account is locked")]
@@ -40,7 +298,219 @@ pub enum EventError<E> {
     #[error("client {0} attempted to dispute transaction {1}, which is already under dispute")]
     DoubleDispute(ClientId, TransactionId),
     #[error("client {0} does not exist")]
-    UnknownClient(ClientId),
-    #[error("state error")]
+    UnknownClient(ClientId, TransactionId),
+    #[error(
+        "client {0} referenced transaction {1}, which did not arrive before the reference expired"
+    )]
+    PendingReferenceExpired(ClientId, TransactionId),
+    #[error(transparent)]
+    Parse(ParseError),
+    #[error("state error: {0}")]
     StateError(#[source] E),
 }
+
+/// A Reason classifies an `EventError` with a code which is stable across releases.
+///
+/// Codes are never reused: new reasons are given new codes, and retired codes stay retired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    DuplicateTransactionId = 1,
+    InsufficientFunds = 2,
+    AccountLocked = 3,
+    DoubleDispute = 4,
+    UnknownClient = 5,
+    PendingReferenceExpired = 6,
+    Parse = 7,
+    StateError = 8,
+}
+
+impl Reason {
+    /// Every reason, in order of their codes.
+    pub const ALL: [Reason; 8] = [
+        Reason::DuplicateTransactionId,
+        Reason::InsufficientFunds,
+        Reason::AccountLocked,
+        Reason::DoubleDispute,
+        Reason::UnknownClient,
+        Reason::PendingReferenceExpired,
+        Reason::Parse,
+        Reason::StateError,
+    ];
+
+    /// The stable numeric code for this reason.
+    pub const fn code(self) -> u16 {
+        self as u16
+    }
+
+    /// The stable name for this reason.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Reason::DuplicateTransactionId => "duplicate-transaction-id",
+            Reason::InsufficientFunds => "insufficient-funds",
+            Reason::AccountLocked => "account-locked",
+            Reason::DoubleDispute => "double-dispute",
+            Reason::UnknownClient => "unknown-client",
+            Reason::PendingReferenceExpired => "pending-reference-expired",
+            Reason::Parse => "parse",
+            Reason::StateError => "state-error",
+        }
+    }
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown reason `{0}`")]
+pub struct UnknownReason(String);
+
+/// A reason is parsed from its stable name.
+impl std::str::FromStr for Reason {
+    type Err = UnknownReason;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Reason::ALL
+            .into_iter()
+            .find(|reason| reason.name() == s)
+            .ok_or_else(|| UnknownReason(s.to_owned()))
+    }
+}
+
+impl<E> EventError<E> {
+    pub fn reason(&self) -> Reason {
+        match self {
+            EventError::DuplicateTransactionId(..) => Reason::DuplicateTransactionId,
+            EventError::InsufficientFunds(..) => Reason::InsufficientFunds,
+            EventError::AccountLocked(..) => Reason::AccountLocked,
+            EventError::DoubleDispute(..) => Reason::DoubleDispute,
+            EventError::UnknownClient(..) => Reason::UnknownClient,
+            EventError::PendingReferenceExpired(..) => Reason::PendingReferenceExpired,
+            EventError::Parse(..) => Reason::Parse,
+            EventError::StateError(..) => Reason::StateError,
+        }
+    }
+
+    /// The client whose event was rejected, where known.
+    pub fn client(&self) -> Option<ClientId> {
+        match self {
+            EventError::DuplicateTransactionId(client, _)
+            | EventError::InsufficientFunds(client, _)
+            | EventError::AccountLocked(client, _)
+            | EventError::DoubleDispute(client, _)
+            | EventError::UnknownClient(client, _)
+            | EventError::PendingReferenceExpired(client, _) => Some(*client),
+            EventError::Parse(_) | EventError::StateError(_) => None,
+        }
+    }
+
+    /// The transaction which was rejected, where known.
+    pub fn tx(&self) -> Option<TransactionId> {
+        match self {
+            EventError::DuplicateTransactionId(_, tx)
+            | EventError::InsufficientFunds(_, tx)
+            | EventError::AccountLocked(_, tx)
+            | EventError::DoubleDispute(_, tx)
+            | EventError::UnknownClient(_, tx)
+            | EventError::PendingReferenceExpired(_, tx) => Some(*tx),
+            EventError::Parse(_) | EventError::StateError(_) => None,
+        }
+    }
+}
+
+/// ErrorRecord is the serialization-friendly form of an `EventError`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorRecord {
+    pub client: Option<ClientId>,
+    pub tx: Option<TransactionId>,
+    pub code: u16,
+    pub reason: Reason,
+    pub message: String,
+}
+
+impl<E> From<&EventError<E>> for ErrorRecord
+where
+    E: std::fmt::Display,
+{
+    fn from(err: &EventError<E>) -> Self {
+        ErrorRecord {
+            client: err.client(),
+            tx: err.tx(),
+            code: err.reason().code(),
+            reason: err.reason(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl<E> Serialize for EventError<E>
+where
+    E: std::fmt::Display,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        ErrorRecord::from(self).serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reason_codes_are_stable() {
+        let errors: [EventError<std::io::Error>; 8] = [
+            EventError::DuplicateTransactionId(1.into(), 1.into()),
+            EventError::InsufficientFunds(1.into(), 1.into()),
+            EventError::AccountLocked(1.into(), 1.into()),
+            EventError::DoubleDispute(1.into(), 1.into()),
+            EventError::UnknownClient(1.into(), 1.into()),
+            EventError::PendingReferenceExpired(1.into(), 1.into()),
+            EventError::Parse(ParseError {
+                origin: None,
+                line: 1,
+                text: String::new(),
+                reason: String::new(),
+            }),
+            EventError::StateError(std::io::ErrorKind::Other.into()),
+        ];
+        let codes: Vec<u16> = errors.iter().map(|err| err.reason().code()).collect();
+        assert_eq!(codes, [1, 2, 3, 4, 5, 6, 7, 8]);
+        let reasons: Vec<Reason> = errors.iter().map(EventError::reason).collect();
+        assert_eq!(reasons, Reason::ALL);
+        for reason in Reason::ALL {
+            let serialized = serde_json::to_value(reason).expect("reasons serialize");
+            assert_eq!(serialized, reason.name());
+            assert_eq!(reason.name().parse::<Reason>().ok(), Some(reason));
+        }
+        assert!("reason".parse::<Reason>().is_err());
+    }
+
+    #[test]
+    fn failing_error_sinks_stop_processing() {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+        drop(receiver);
+        let event = |event_type, tx| Event {
+            event_type,
+            client: 1.into(),
+            tx: TransactionId::from(tx),
+            amount: "1.0".parse().expect("valid amount"),
+        };
+        let records = [
+            Ok(event(EventType::Withdrawal, 1)),
+            Ok(event(EventType::Deposit, 2)),
+        ];
+        let mut state = state::memory::MemoryState::default();
+        let counts = feed_records(&mut state, records, Some(&sender));
+
+        assert!(counts.stopped);
+        assert_eq!(counts.records, 1);
+        assert!(counts.check_complete().is_err());
+        assert!(ProcessCounts::default().check_complete().is_ok());
+    }
+}
@@ -0,0 +1,345 @@
+//! Linting event streams for records which are valid, but almost certainly wrong.
+//!
+//! The engine tolerates these records: it ignores stray amounts, rejects withdrawals from unknown
+//! clients, and so on. They usually indicate a problem upstream, so it is worth flagging them
+//! before processing rather than discovering their effects afterwards.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use serde::Serialize;
+
+use crate::{
+    input::ParseError,
+    primitives::{ClientId, Event, EventType, TransactionId},
+};
+
+/// How seriously a finding should be taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The record is processed, but probably not as its author intended.
+    Warning,
+    /// The record will be rejected by the engine.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A Rule is a kind of suspicious record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    /// A dispute, resolve, or chargeback carries an amount, which is ignored.
+    AmountOnReference,
+    /// A deposit of zero.
+    ZeroDeposit,
+    /// A withdrawal from a client who has made no deposits.
+    WithdrawalFromUnknownClient,
+    /// A deposit reuses the id of an earlier deposit.
+    DuplicateTransactionId,
+    /// A dispute, resolve, or chargeback submitted by a client other than the transaction's owner.
+    ReferenceFromNonOwner,
+    /// A withdrawal reuses the id of an earlier transaction, or a deposit the id of an earlier
+    /// withdrawal.
+    ReusedTransactionId,
+}
+
+impl Rule {
+    /// Every rule, in order of their codes.
+    pub const ALL: [Rule; 6] = [
+        Rule::AmountOnReference,
+        Rule::ZeroDeposit,
+        Rule::WithdrawalFromUnknownClient,
+        Rule::DuplicateTransactionId,
+        Rule::ReferenceFromNonOwner,
+        Rule::ReusedTransactionId,
+    ];
+
+    /// The stable code identifying this rule.
+    pub const fn code(self) -> &'static str {
+        match self {
+            Rule::AmountOnReference => "L001",
+            Rule::ZeroDeposit => "L002",
+            Rule::WithdrawalFromUnknownClient => "L003",
+            Rule::DuplicateTransactionId => "L004",
+            Rule::ReferenceFromNonOwner => "L005",
+            Rule::ReusedTransactionId => "L006",
+        }
+    }
+
+    pub const fn severity(self) -> Severity {
+        match self {
+            Rule::AmountOnReference
+            | Rule::ZeroDeposit
+            | Rule::ReferenceFromNonOwner
+            | Rule::ReusedTransactionId => Severity::Warning,
+            Rule::WithdrawalFromUnknownClient | Rule::DuplicateTransactionId => Severity::Error,
+        }
+    }
+}
+
+/// A Finding is a single suspicious record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub code: &'static str,
+    pub rule: Rule,
+    pub severity: Severity,
+    /// The position of the record in the stream, counting from 1 and including malformed records.
+    pub record: usize,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(rule: Rule, record: usize, event: &Event, message: String) -> Self {
+        Finding {
+            code: rule.code(),
+            rule,
+            severity: rule.severity(),
+            record,
+            client: event.client,
+            tx: event.tx,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {}: {} {}: {}",
+            self.record, self.code, self.severity, self.message
+        )
+    }
+}
+
+/// A LintReport collects every finding from a stream, with counts by severity.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct LintReport {
+    pub records: usize,
+    pub malformed: usize,
+    pub warnings: usize,
+    pub errors: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl LintReport {
+    fn push(&mut self, finding: Finding) {
+        match finding.severity {
+            Severity::Warning => self.warnings += 1,
+            Severity::Error => self.errors += 1,
+        }
+        self.findings.push(finding);
+    }
+}
+
+/// A Linter checks each event against what it has seen of the stream so far.
+///
+/// It keeps the owner and type of every deposit and withdrawal, so its memory grows with the input.
+#[derive(Debug, Default)]
+pub struct Linter {
+    owners: HashMap<TransactionId, (ClientId, EventType)>,
+    depositors: HashSet<ClientId>,
+    report: LintReport,
+}
+
+impl Linter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check a record, which may have failed to parse.
+    ///
+    /// Malformed records produce no findings, but are counted so that record numbers line up with
+    /// the input.
+    pub fn check_record(&mut self, record: &Result<Event, ParseError>) -> &[Finding] {
+        match record {
+            Ok(event) => self.check(event),
+            Err(_) => {
+                self.report.records += 1;
+                self.report.malformed += 1;
+                &[]
+            }
+        }
+    }
+
+    /// Check an event, returning any findings it produces.
+    pub fn check(&mut self, event: &Event) -> &[Finding] {
+        self.report.records += 1;
+        let record = self.report.records;
+        let before = self.report.findings.len();
+        let mut flag = |rule: Rule, message: String| {
+            self.report.push(Finding::new(rule, record, event, message))
+        };
+
+        match event.event_type {
+            EventType::Deposit | EventType::Withdrawal => {
+                if event.event_type == EventType::Deposit && event.amount.is_zero() {
+                    flag(Rule::ZeroDeposit, "deposit of zero".into());
+                }
+                if event.event_type == EventType::Withdrawal
+                    && !self.depositors.contains(&event.client)
+                {
+                    flag(
+                        Rule::WithdrawalFromUnknownClient,
+                        format!(
+                            "withdrawal from client {}, who has made no deposits",
+                            event.client
+                        ),
+                    );
+                }
+                let kind = event.event_type;
+                let rejected = match self.owners.get(&event.tx).copied() {
+                    // the engine rejects a deposit only when its id is already held by a deposit
+                    Some((owner, EventType::Deposit)) if kind == EventType::Deposit => {
+                        flag(
+                            Rule::DuplicateTransactionId,
+                            format!(
+                                "transaction {} was already deposited by client {owner}",
+                                event.tx
+                            ),
+                        );
+                        true
+                    }
+                    Some((owner, earlier)) => {
+                        flag(
+                            Rule::ReusedTransactionId,
+                            format!(
+                                "transaction {} was already used by client {owner} for a {earlier}",
+                                event.tx
+                            ),
+                        );
+                        // a deposit becomes the transaction which later references refer to
+                        if kind == EventType::Deposit {
+                            self.owners.insert(event.tx, (event.client, kind));
+                        }
+                        false
+                    }
+                    None => {
+                        self.owners.insert(event.tx, (event.client, kind));
+                        false
+                    }
+                };
+                if kind == EventType::Deposit && !rejected {
+                    self.depositors.insert(event.client);
+                }
+            }
+            EventType::Dispute | EventType::Resolve | EventType::Chargeback => {
+                let kind = event.event_type;
+                if !event.amount.is_zero() {
+                    flag(
+                        Rule::AmountOnReference,
+                        format!(
+                            "{kind} carries an amount of {}, which is ignored",
+                            event.amount
+                        ),
+                    );
+                }
+                if let Some((owner, _)) = self.owners.get(&event.tx) {
+                    if *owner != event.client {
+                        flag(
+                            Rule::ReferenceFromNonOwner,
+                            format!(
+                                "{kind} from client {} of transaction {}, which belongs to client {owner}",
+                                event.client, event.tx
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+
+        &self.report.findings[before..]
+    }
+
+    /// Finish linting, returning every finding.
+    pub fn finish(self) -> LintReport {
+        self.report
+    }
+}
+
+/// Lint a whole stream of events.
+pub fn lint<I>(events: I) -> LintReport
+where
+    I: IntoIterator<Item = Event>,
+{
+    let mut linter = Linter::new();
+    for event in events {
+        linter.check(&event);
+    }
+    linter.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: EventType, client: u16, tx: u32, amount: &str) -> Event {
+        Event {
+            event_type,
+            client: client.into(),
+            tx: tx.into(),
+            amount: amount.parse().expect("test amounts are valid"),
+        }
+    }
+
+    #[test]
+    fn each_rule_is_flagged() {
+        use EventType::*;
+
+        let report = lint([
+            event(Deposit, 1, 1, "1"),
+            event(Dispute, 1, 1, "1"),
+            event(Deposit, 1, 2, "0"),
+            event(Withdrawal, 2, 3, "1"),
+            event(Deposit, 2, 1, "1"),
+            event(Resolve, 2, 2, "0"),
+            event(Withdrawal, 1, 4, "0.5"),
+            event(Withdrawal, 1, 2, "0.5"),
+            event(Deposit, 1, 4, "1"),
+            event(Withdrawal, 2, 5, "1"),
+        ]);
+
+        let found: Vec<_> = report
+            .findings
+            .iter()
+            .map(|finding| (finding.record, finding.code))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (2, "L001"),
+                (3, "L002"),
+                (4, "L003"),
+                (5, "L004"),
+                (6, "L005"),
+                (8, "L006"),
+                (9, "L006"),
+                // the duplicate deposit of record 5 was rejected, so client 2 has still deposited
+                // nothing
+                (10, "L003"),
+            ]
+        );
+        assert_eq!(report.records, 10);
+        assert_eq!(report.warnings, 5);
+        assert_eq!(report.errors, 3);
+    }
+
+    #[test]
+    fn rule_codes_are_distinct() {
+        let codes: HashSet<_> = Rule::ALL.iter().map(|rule| rule.code()).collect();
+        assert_eq!(codes.len(), Rule::ALL.len());
+    }
+}
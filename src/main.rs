@@ -1,70 +1,1468 @@
-use std::path::PathBuf;
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::Write,
+    ops::Bound,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::SyncSender,
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use clap::Parser;
+use clap::{ArgEnum, ArgGroup, Args, Parser, Subcommand};
 use transacty::{
-    process_events,
-    state::{memory::MemoryState, StateManager},
+    checkpoint::Checkpoint,
+    diff::{diff, read_state},
+    feed_records, finish_processing,
+    follow::{FollowConfig, Follower},
+    input::{
+        expand_inputs, read_binary_events, read_events, read_ndjson_events, read_positioned_events,
+        resume_positioned_events, Compression, InputFormat, InputSource, ParseError, Position,
+    },
+    lint::Linter,
+    mapping::InputMapping,
+    output::{
+        write_clients, write_diff, write_history, write_reconciliation, write_statement,
+        DiffFormat, ErrorFormat, ErrorWriter, EventWriter, OutputFormat, ReconcileFormat,
+        StatementFormat,
+    },
+    pipeline::{parse_pipelined, PipelineConfig},
+    primitives::{ClientId, ClientState, Event, EventType, SerializeClientState, TransactionId},
+    reconcile::{read_clients, reconcile},
+    sharded::{feed_records_parallel, ShardedState},
+    state::{
+        dense::DenseClientTable,
+        deposits::{DepositStats, SpillConfig},
+        memory::{ClientTable, GenericMemoryState, PendingConfig},
+        snapshot::Snapshot,
+        SortKey, StateManager,
+    },
+    statement::Statement,
+    summary::Summary,
+    EventError, ProcessCounts, Reason,
 };
 
+/// A stream of parsed records from a single input.
+type Records<'a> = Box<dyn 'a + Iterator<Item = Result<Event, ParseError>>>;
+
+/// The state produced by a run, whichever client table and sharding it uses.
+type State<'a> = &'a dyn StateManager<Err = std::io::Error>;
+
+/// Somewhere to record the state part-way through a run.
+type Interim<'a> = &'a dyn Fn(State) -> Result<(), Box<dyn std::error::Error>>;
+
+/// The memory layout of the client table.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// Clients are stored in a hash map and emitted in arbitrary order.
+    Hash,
+    /// Clients are stored in a table indexed by id and emitted in order of their id.
+    Dense,
+}
+
+/// The order in which client state is written: a `SortKey`, or none at all.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Sort {
+    /// Whatever order the client table produces; never buffers the state.
+    None,
+    Client,
+    Available,
+    Held,
+    Total,
+}
+
+impl Sort {
+    fn key(self) -> Option<SortKey> {
+        match self {
+            Sort::None => None,
+            Sort::Client => Some(SortKey::Client),
+            Sort::Available => Some(SortKey::Available),
+            Sort::Held => Some(SortKey::Held),
+            Sort::Total => Some(SortKey::Total),
+        }
+    }
+}
+
+/// The format of the report written by `validate`.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ReportFormat {
+    /// One line per malformed record or finding, followed by a summary on stderr.
+    Text,
+    /// A single JSON object containing counts and every finding.
+    Json,
+    /// One row per finding.
+    Csv,
+}
+
+/// A toy transaction engine.
+///
+/// Without a subcommand, events are processed as by `process`.
 #[derive(Parser, Debug)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// Path to the input CSV file.
-    #[clap(parse(from_os_str))]
-    input: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Process events and write the resulting client state.
+    Process(ProcessArgs),
+    /// Parse and lint inputs, reporting malformed and suspicious records without processing any
+    /// events.
+    Validate(ValidateArgs),
+    /// Process events, or read a checkpoint, and show the state of one client, along with the
+    /// events they submitted.
+    Inspect(InspectArgs),
+    /// Process events up to a given point and write the client state as of that point.
+    Replay(ReplayArgs),
+    /// Rewrite events in another format, without processing them.
+    Convert(ConvertArgs),
+    /// Process events and write a statement of one client's account, with running balances.
+    Statement(StatementArgs),
+    /// Process events and compare the resulting client state against an expected one, reporting
+    /// every break.
+    Reconcile(ReconcileArgs),
+    /// Compare two sets of client state, such as the outputs of two runs, reporting how each
+    /// client changed.
+    Diff(DiffArgs),
+}
+
+/// Arguments which describe the format and layout of the inputs.
+#[derive(Args, Debug)]
+struct FormatArgs {
+    /// Format of the inputs.
+    #[clap(long, arg_enum, default_value = "csv")]
+    input_format: InputFormat,
+
+    /// Read the layout of CSV inputs from this TOML file; the flags below take precedence.
+    #[clap(long, parse(from_os_str))]
+    mapping: Option<PathBuf>,
+
+    /// The source column holding a field, as FIELD=COLUMN, where FIELD is one of `type`,
+    /// `client`, `tx`, or `amount`.
+    #[clap(long, value_delimiter = ',')]
+    column: Vec<String>,
+
+    /// An alternative name for an event type, as ALIAS=TYPE, such as `DEP=deposit`.
+    #[clap(long, value_delimiter = ',')]
+    event_type: Vec<String>,
+
+    /// The character separating fields of CSV inputs; `\t` for a tab.
+    #[clap(long, parse(try_from_str = ascii_byte))]
+    delimiter: Option<u8>,
+
+    /// The character quoting fields of CSV inputs.
+    #[clap(long, parse(try_from_str = ascii_byte))]
+    quote: Option<u8>,
+
+    /// CSV inputs have no header row; their columns are these, in order.
+    #[clap(long, value_delimiter = ',')]
+    header_names: Option<Vec<String>>,
+
+    /// CSV inputs have no header row; their columns are `type`, `client`, `tx`, and `amount`, in
+    /// that order.
+    #[clap(long, conflicts_with = "header-names")]
+    no_header: bool,
+}
+
+/// Parse a single ASCII character, or `\t` for a tab.
+fn ascii_byte(arg: &str) -> Result<u8, String> {
+    match arg.as_bytes() {
+        [byte] if byte.is_ascii() => Ok(*byte),
+        b"\\t" => Ok(b'\t'),
+        _ => Err(format!("`{arg}` is not a single ASCII character")),
+    }
+}
+
+/// Split a flag value of the form KEY=VALUE.
+fn key_value<'a>(flag: &str, arg: &'a str) -> Result<(&'a str, &'a str), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .ok_or_else(|| format!("--{flag} expects KEY=VALUE, not `{arg}`"))
+}
 
-    /// Emit errors to stdout during processing.
+impl FormatArgs {
+    /// The layout of CSV inputs: the mapping file, if any, overridden by the flags.
+    fn mapping(&self) -> Result<InputMapping, Box<dyn std::error::Error>> {
+        let mut mapping = match &self.mapping {
+            Some(path) => InputMapping::from_toml(&std::fs::read_to_string(path)?)
+                .map_err(|err| format!("{}: {err}", path.display()))?,
+            None => InputMapping::default(),
+        };
+        for arg in &self.column {
+            let (field, column) = key_value("column", arg)?;
+            let columns = &mut mapping.columns;
+            let target = match field {
+                "type" => &mut columns.event_type,
+                "client" => &mut columns.client,
+                "tx" => &mut columns.tx,
+                "amount" => &mut columns.amount,
+                _ => return Err(format!("--column: `{field}` is not a field of an event").into()),
+            };
+            *target = column.to_owned();
+        }
+        for arg in &self.event_type {
+            let (alias, name) = key_value("event-type", arg)?;
+            let event_type = EventType::ALL
+                .into_iter()
+                .find(|event_type| event_type.name() == name)
+                .ok_or_else(|| format!("--event-type: `{name}` is not an event type"))?;
+            mapping.event_types.insert(alias.to_owned(), event_type);
+        }
+        if let Some(delimiter) = self.delimiter {
+            mapping.delimiter = delimiter;
+        }
+        if let Some(quote) = self.quote {
+            mapping.quote = quote;
+        }
+        if let Some(names) = &self.header_names {
+            mapping.header = Some(names.clone());
+        } else if self.no_header {
+            let names = ["type", "client", "tx", "amount"];
+            mapping.header = Some(names.map(String::from).to_vec());
+        }
+
+        if self.input_format != InputFormat::Csv && mapping != InputMapping::default() {
+            return Err("column mappings and CSV options require CSV input".into());
+        }
+        Ok(mapping)
+    }
+}
+
+/// Arguments which select inputs and configure the engine.
+#[derive(Args, Debug)]
+struct EngineArgs {
+    /// Input files, processed in order into the same state.
+    ///
+    /// `-` reads from stdin. A directory stands for the files directly within it, sorted by name.
+    /// A glob pattern stands for the files it matches, sorted by path. Inputs compressed with gzip
+    /// or zstd are decompressed, recognized by a `.gz` or `.zst` extension or their leading bytes.
+    ///
+    /// `inspect` may be given `--checkpoint` instead, to read the state from the checkpoint.
+    #[clap(required_unless_present = "checkpoint")]
+    inputs: Vec<String>,
+
+    #[clap(flatten)]
+    format: FormatArgs,
+
+    /// Emit errors to stderr during processing.
     #[clap(short, long)]
     debug: bool,
+
+    /// Format of the errors emitted during processing.
+    #[clap(long, arg_enum, default_value = "text")]
+    errors_format: ErrorFormat,
+
+    /// Write errors to this file instead of stderr; implies `--debug`.
+    #[clap(long, parse(from_os_str))]
+    errors_file: Option<PathBuf>,
+
+    /// Defer disputes, resolves, and chargebacks of unknown transactions until their deposit
+    /// arrives, holding at most this many at once.
+    #[clap(long)]
+    pending_max_count: Option<usize>,
+
+    /// Defer disputes, resolves, and chargebacks of unknown transactions until their deposit
+    /// arrives, for at most this many subsequent events.
+    #[clap(long)]
+    pending_max_age: Option<u64>,
+
+    /// Partition clients across this many worker threads.
+    ///
+    /// A deferred reference is held by the shard of the client who made it, which may not own
+    /// the deposit it awaits, so sharding cannot be combined with deferral.
+    #[clap(
+        long,
+        default_value_t = 1,
+        conflicts_with_all = &["pending-max-count", "pending-max-age"]
+    )]
+    shards: usize,
+
+    /// Parse input on this many background threads, separately from event processing.
+    ///
+    /// When 0, input is parsed inline as events are processed.
+    #[clap(long, default_value_t = 0)]
+    parse_threads: usize,
+
+    /// Abort without writing any state once more than this many records have failed to parse.
+    ///
+    /// Malformed records are otherwise reported as errors and skipped.
+    #[clap(long)]
+    max_parse_errors: Option<usize>,
+
+    /// Exit unsuccessfully if any error with one of these reasons is reported.
+    ///
+    /// The client state is still written.
+    #[clap(long, value_delimiter = ',', possible_values = Reason::ALL.map(Reason::name))]
+    fail_on: Vec<Reason>,
+
+    /// Exit unsuccessfully if more than this many errors of any kind are reported.
+    ///
+    /// The client state is still written.
+    #[clap(long)]
+    max_errors: Option<usize>,
+
+    /// Memory layout of the client table.
+    #[clap(long, arg_enum, default_value = "hash")]
+    layout: Layout,
+
+    /// Spill deposit records to disk when they use approximately this many bytes of memory.
+    #[clap(long)]
+    deposit_memory_ceiling: Option<usize>,
+
+    /// Directory for spilled deposit records; defaults to the system temporary directory.
+    #[clap(long, parse(from_os_str))]
+    spill_dir: Option<PathBuf>,
+
+    /// Report the storage used by deposit records to stderr after processing.
+    #[clap(long)]
+    memory_report: bool,
+
+    /// Print a summary of the run to stderr after processing.
+    #[clap(long)]
+    summary: bool,
+
+    /// Write a summary of the run to this file as JSON after processing.
+    #[clap(long, parse(from_os_str))]
+    summary_file: Option<PathBuf>,
+
+    /// Keep reading a single input file as it grows, until interrupted by SIGINT or SIGTERM.
+    ///
+    /// A trailing line without a newline is not read until its newline arrives.
+    #[clap(long)]
+    follow: bool,
+
+    /// While following, write the client state this often, in seconds, if it has changed.
+    ///
+    /// The checkpoint file, if any, is written at the same time.
+    #[clap(long, default_value_t = 10, requires = "follow")]
+    checkpoint_interval: u64,
+
+    /// Record progress through a single input file, with a snapshot of the state, in this file.
+    ///
+    /// The checkpoint is written every `--checkpoint-every` records, and once the input ends.
+    #[clap(long, parse(from_os_str), conflicts_with = "parse-threads")]
+    checkpoint: Option<PathBuf>,
+
+    /// Write the checkpoint after every this many records.
+    #[clap(long, default_value_t = 1_000_000, requires = "checkpoint")]
+    checkpoint_every: usize,
+
+    /// Resume from the checkpoint file, if it exists, rather than from the start of the input.
+    ///
+    /// The input must be the same file, possibly grown, and the engine options must match.
+    #[clap(long, requires = "checkpoint")]
+    resume: bool,
+
+    /// Write every event admitted for processing to this Parquet file, with decimal amounts.
+    #[cfg(feature = "parquet")]
+    #[clap(long, parse(from_os_str))]
+    export_events: Option<PathBuf>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+impl EngineArgs {
+    fn make_state<Clients>(&self, history: bool) -> GenericMemoryState<Clients>
+    where
+        Clients: ClientTable + Default,
+    {
+        let state = GenericMemoryState::new(self.pending_config(), self.spill_config());
+        if history {
+            state.with_history()
+        } else {
+            state
+        }
+    }
+
+    fn spill_config(&self) -> Option<SpillConfig> {
+        Some(SpillConfig {
+            memory_ceiling: self.deposit_memory_ceiling?,
+            directory: self.spill_dir.clone().unwrap_or_else(std::env::temp_dir),
+        })
+    }
+
+    /// Fail if the errors reported cross the thresholds set by `--fail-on` and `--max-errors`.
+    ///
+    /// The exit code reflects the most serious kind of error which counted towards a threshold.
+    fn check_thresholds(&self, counts: &ProcessCounts) -> Result<(), Failure> {
+        let over_max = self.max_errors.is_some_and(|max| counts.errors > max);
+        let failing: Vec<_> = counts
+            .reasons
+            .iter()
+            .filter(|(reason, count)| *count > 0 && (over_max || self.fail_on.contains(reason)))
+            .collect();
+        let code = match failing
+            .iter()
+            .map(|(reason, _)| Failure::code(*reason))
+            .max()
+        {
+            Some(code) => code,
+            None => return Ok(()),
+        };
+        let tally: Vec<_> = failing
+            .iter()
+            .map(|(reason, count)| format!("{count} {reason}"))
+            .collect();
+        Err(Failure::new(
+            code,
+            format!("{} errors reported: {}", counts.errors, tally.join(", ")),
+        ))
+    }
+
+    fn pending_config(&self) -> Option<PendingConfig> {
+        if self.pending_max_count.is_none() && self.pending_max_age.is_none() {
+            return None;
+        }
+        let default = PendingConfig::default();
+        Some(PendingConfig {
+            max_count: self.pending_max_count.unwrap_or(default.max_count),
+            max_age: self.pending_max_age.unwrap_or(default.max_age),
+        })
+    }
+}
+
+/// Arguments which control how client state is written.
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// Format of the client state written after processing.
+    #[clap(long, arg_enum, default_value = "csv")]
+    output_format: OutputFormat,
+
+    /// Write client state in ascending order of this column, breaking ties by client id.
+    ///
+    /// The dense layout streams clients in id order; other orders collect the state to sort it.
+    #[clap(long, arg_enum, default_value = "client")]
+    sort: Sort,
+
+    /// Write client state to this file instead of stdout.
+    #[clap(long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ProcessArgs {
+    #[clap(flatten)]
+    engine: EngineArgs,
+
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+struct ValidateArgs {
+    /// Input files, as for `process`.
+    #[clap(required = true)]
+    inputs: Vec<String>,
+
+    #[clap(flatten)]
+    format: FormatArgs,
+
+    /// Parse input on this many background threads.
+    #[clap(long, default_value_t = 0)]
+    parse_threads: usize,
+
+    /// Format of the report written to stdout.
+    ///
+    /// Malformed records are always reported as text; in the machine-readable formats, they go to
+    /// stderr.
+    #[clap(long, arg_enum, default_value = "text")]
+    report: ReportFormat,
+}
+
+#[derive(Args, Debug)]
+struct InspectArgs {
+    /// The client to show.
+    client: ClientId,
+
+    #[clap(flatten)]
+    engine: EngineArgs,
+}
+
+#[derive(Args, Debug)]
+#[clap(group(ArgGroup::new("stop").required(true).args(&["until-record", "until-tx"])))]
+struct ReplayArgs {
+    /// Stop after this many records, counted across all inputs.
+    #[clap(long)]
+    until_record: Option<usize>,
+
+    /// Stop after the first record of this transaction.
+    #[clap(long)]
+    until_tx: Option<TransactionId>,
+
+    #[clap(flatten)]
+    engine: EngineArgs,
+
+    #[clap(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args, Debug)]
+struct StatementArgs {
+    /// The client whose statement is written.
+    client: ClientId,
+
+    /// Begin the statement at this event, numbered in processing order from 1; the events before
+    /// it make up the opening balances.
+    ///
+    /// Events are numbered as they are handled, so malformed records are not counted. Sharding
+    /// would number each shard's events separately, so it cannot be combined with a statement.
+    #[clap(long)]
+    from_seq: Option<u64>,
+
+    /// End the statement after this event.
+    #[clap(long)]
+    to_seq: Option<u64>,
+
+    /// Format of the statement.
+    #[clap(long, arg_enum, default_value = "text")]
+    format: StatementFormat,
+
+    /// Write the statement to this file instead of stdout.
+    #[clap(long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    engine: EngineArgs,
+}
+
+#[derive(Args, Debug)]
+struct ReconcileArgs {
+    /// The expected client state: CSV with the columns `client,available,held,total,locked`.
+    #[clap(long, parse(from_os_str))]
+    expected: PathBuf,
+
+    /// Format of the report.
+    #[clap(long, arg_enum, default_value = "text")]
+    report: ReconcileFormat,
+
+    /// Write the report to this file instead of stdout.
+    #[clap(long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    engine: EngineArgs,
+}
+
+#[derive(Args, Debug)]
+struct DiffArgs {
+    /// The earlier client state: a checkpoint, or client state written as CSV or binary.
+    #[clap(parse(from_os_str))]
+    before: PathBuf,
+
+    /// The later client state, in any of the same forms.
+    #[clap(parse(from_os_str))]
+    after: PathBuf,
+
+    /// Format of the report.
+    #[clap(long, arg_enum, default_value = "text")]
+    report: DiffFormat,
+
+    /// Write the report to this file instead of stdout.
+    #[clap(long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ConvertArgs {
+    /// Input files, as for `process`, written out in order as a single stream.
+    #[clap(required = true)]
+    inputs: Vec<String>,
+
+    #[clap(flatten)]
+    format: FormatArgs,
+
+    /// Format to write the events in.
+    #[clap(long, arg_enum)]
+    to: InputFormat,
+
+    /// Write the events to this file instead of stdout.
+    #[clap(long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::from(
+                err.downcast_ref::<Failure>()
+                    .map_or(1, |failure| failure.code),
+            )
+        }
+    }
+}
+
+fn try_main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
-    let reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .comment(Some(b'#'))
-        .from_path(&cli.input)?;
+    match cli.command.unwrap_or(Command::Process(cli.process)) {
+        Command::Process(args) => {
+            let session = Session::new(&args.engine, StopPoint::default(), false)?;
+            let interim = |state: State| write_state(&args.output, state);
+            with_engine(&args.engine, &session, &interim, interim)
+        }
+        Command::Validate(args) => validate(&args),
+        Command::Convert(args) => convert(&args),
+        Command::Diff(args) => diff_states(&args),
+        Command::Inspect(args) if args.engine.inputs.is_empty() => {
+            let path = args
+                .engine
+                .checkpoint
+                .as_ref()
+                .expect("inputs are only omitted with a checkpoint");
+            let (_, state) = Checkpoint::read_client_view(path)
+                .map_err(|err| format!("{}: {err}", path.display()))?;
+            inspect(args.client, &state)
+        }
+        Command::Inspect(args) => {
+            let session = Session::new(&args.engine, StopPoint::default(), true)?;
+            with_engine(&args.engine, &session, &|_| Ok(()), |state| {
+                inspect(args.client, state)
+            })
+        }
+        Command::Statement(args) => {
+            if args.engine.shards > 1 {
+                return Err(
+                    "statement numbers events across the whole input, so --shards cannot be used"
+                        .into(),
+                );
+            }
+            let session = Session::new(&args.engine, StopPoint::default(), true)?;
+            with_engine(&args.engine, &session, &|_| Ok(()), |state| {
+                statement(&args, state)
+            })
+        }
+        Command::Reconcile(args) => {
+            let path = &args.expected;
+            let expected = std::fs::File::open(path)
+                .map_err(|err| format!("{}: {err}", path.display()))
+                .and_then(|file| {
+                    read_clients(std::io::BufReader::new(file))
+                        .map_err(|err| format!("{}: {err}", path.display()))
+                })?;
+            let session = Session::new(&args.engine, StopPoint::default(), false)?;
+            with_engine(&args.engine, &session, &|_| Ok(()), |state| {
+                reconcile_state(&args, expected, state)
+            })
+        }
+        Command::Replay(args) => {
+            let stop = StopPoint::new(args.until_record, args.until_tx);
+            let session = Session::new(&args.engine, stop, false)?;
+            let interim = |state: State| write_state(&args.output, state);
+            with_engine(&args.engine, &session, &interim, |state| {
+                if !session.stop.reached() {
+                    eprintln!("replay: the input ended before the requested point");
+                }
+                write_state(&args.output, state)
+            })
+        }
+    }
+}
+
+/// Failure ends the process with an exit code which reflects its cause.
+///
+/// Other errors, such as unreadable inputs, exit with code 1; invalid arguments exit with code 2.
+#[derive(Debug)]
+struct Failure {
+    code: u8,
+    message: String,
+}
+
+impl Failure {
+    /// Records could not be parsed.
+    const PARSE: u8 = 3;
+    /// Events were rejected by business rules.
+    const REJECTED: u8 = 4;
+    /// The state backend failed.
+    const STATE: u8 = 5;
+    /// The computed state disagreed with the expected state.
+    const BREAK: u8 = 6;
+
+    fn new(code: u8, message: impl Into<String>) -> Self {
+        Failure {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The exit code for errors with the given reason.
+    fn code(reason: Reason) -> u8 {
+        match reason {
+            Reason::Parse => Failure::PARSE,
+            Reason::StateError => Failure::STATE,
+            _ => Failure::REJECTED,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// ParseErrorLimit counts malformed records, and ends the input once too many have been seen.
+struct ParseErrorLimit {
+    max: Option<usize>,
+    seen: Cell<usize>,
+}
+
+impl ParseErrorLimit {
+    fn new(max: Option<usize>) -> Self {
+        ParseErrorLimit {
+            max,
+            seen: Cell::new(0),
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.max.is_some_and(|max| self.seen.get() > max)
+    }
+
+    /// Whether `record` should be processed.
+    ///
+    /// The record which exceeds the limit is still admitted, so that it is reported.
+    fn admit(&self, record: &Result<Event, ParseError>) -> bool {
+        if self.exceeded() {
+            return false;
+        }
+        if record.is_err() {
+            self.seen.set(self.seen.get() + 1);
+        }
+        true
+    }
+
+    fn check(&self) -> Result<(), Failure> {
+        if self.exceeded() {
+            Err(Failure::new(
+                Failure::PARSE,
+                format!("aborted after {} malformed records", self.seen.get()),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// StopPoint ends the input at a given record or transaction.
+#[derive(Default)]
+struct StopPoint {
+    until_record: Option<usize>,
+    until_tx: Option<TransactionId>,
+    records: Cell<usize>,
+    tx_seen: Cell<bool>,
+}
+
+impl StopPoint {
+    fn new(until_record: Option<usize>, until_tx: Option<TransactionId>) -> Self {
+        StopPoint {
+            until_record,
+            until_tx,
+            ..StopPoint::default()
+        }
+    }
+
+    fn reached(&self) -> bool {
+        self.tx_seen.get()
+            || self
+                .until_record
+                .is_some_and(|max| self.records.get() >= max)
+    }
+
+    /// Whether `record` comes before the stop point.
+    ///
+    /// The record of the transaction named by `until_tx` is itself admitted.
+    fn admit(&self, record: &Result<Event, ParseError>) -> bool {
+        if self.reached() {
+            return false;
+        }
+        self.records.set(self.records.get() + 1);
+        if let Ok(event) = record {
+            if Some(event.tx) == self.until_tx {
+                self.tx_seen.set(true);
+            }
+        }
+        true
+    }
+}
+
+/// Session carries the bookkeeping which spans every input of a single run.
+struct Session {
+    parse_errors: ParseErrorLimit,
+    stop: StopPoint,
+    /// Whether the state keeps a history of the events applied to each client, for `inspect`.
+    history: bool,
+    /// Every admitted event is written here, if `--export-events` was given.
+    #[cfg(feature = "parquet")]
+    export: std::cell::RefCell<Option<EventExport>>,
+}
+
+impl Session {
+    fn new(
+        engine: &EngineArgs,
+        stop: StopPoint,
+        history: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Session {
+            parse_errors: ParseErrorLimit::new(engine.max_parse_errors),
+            stop,
+            history,
+            #[cfg(feature = "parquet")]
+            export: std::cell::RefCell::new(match &engine.export_events {
+                Some(path) => Some(EventExport::create(path)?),
+                None => None,
+            }),
+        })
+    }
+
+    /// Whether `record` should be processed.
+    fn admit(&self, record: &Result<Event, ParseError>) -> bool {
+        if !(self.stop.admit(record) && self.parse_errors.admit(record)) {
+            return false;
+        }
+        #[cfg(feature = "parquet")]
+        if let (Ok(event), Some(export)) = (record, self.export.borrow_mut().as_mut()) {
+            if !export.write(event) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Complete the files written during the session.
+    fn finish(&self) -> Result<(), Box<dyn std::error::Error>> {
+        #[cfg(feature = "parquet")]
+        if let Some(export) = self.export.take() {
+            export.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Whether no further input should be read.
+    fn done(&self) -> bool {
+        self.stop.reached() || self.parse_errors.exceeded()
+    }
+}
+
+/// EventExport writes each event admitted for processing to a Parquet file.
+///
+/// A failure to write ends the input, as too many malformed records do, and is reported when the
+/// export is finished.
+#[cfg(feature = "parquet")]
+struct EventExport {
+    writer: transacty::columnar::EventWriter<std::io::BufWriter<std::fs::File>>,
+    failed: Option<parquet::errors::ParquetError>,
+}
+
+#[cfg(feature = "parquet")]
+impl EventExport {
+    fn create(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let file =
+            std::fs::File::create(path).map_err(|err| format!("{}: {err}", path.display()))?;
+        Ok(EventExport {
+            writer: transacty::columnar::EventWriter::new(std::io::BufWriter::new(file))?,
+            failed: None,
+        })
+    }
+
+    /// Whether the event was written.
+    fn write(&mut self, event: &Event) -> bool {
+        if self.failed.is_none() {
+            self.failed = self.writer.write(event).err();
+        }
+        self.failed.is_none()
+    }
+
+    fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(err) = self.failed {
+            return Err(format!("exporting events: {err}").into());
+        }
+        self.writer.finish()?.flush()?;
+        Ok(())
+    }
+}
+
+/// Read records from a single source, inline or on `parse_threads` background threads.
+fn read_source(
+    parse_threads: usize,
+    format: InputFormat,
+    mapping: &InputMapping,
+    source: &InputSource,
+) -> Result<Records<'static>, Box<dyn std::error::Error>> {
+    if format != InputFormat::Csv && parse_threads > 0 {
+        return Err("--parse-threads requires CSV input".into());
+    }
+    let input = source.open()?;
+    match format {
+        InputFormat::Csv => {}
+        InputFormat::Ndjson => return Ok(Box::new(read_ndjson_events(input))),
+        InputFormat::Binary => {
+            let events = read_binary_events(input).map_err(|err| format!("{source}: {err}"))?;
+            return Ok(Box::new(events));
+        }
+    }
+    Ok(if parse_threads > 0 {
+        let config = PipelineConfig {
+            parsers: parse_threads,
+            ..PipelineConfig::default()
+        };
+        Box::new(parse_pipelined(input, mapping.clone(), config))
+    } else {
+        Box::new(read_events(mapping, input))
+    })
+}
 
-    let (errors, join_handle) = if cli.debug {
+/// Feed the records of each source into `state` in turn, using `feed`.
+///
+/// When there is more than one source, parse errors are attributed to their source, and the
+/// counts for each source are reported to stderr.
+fn feed_sources<S>(
+    engine: &EngineArgs,
+    sources: &[InputSource],
+    session: &Session,
+    state: &mut S,
+    feed: impl FnMut(&mut S, Records<'_>) -> ProcessCounts,
+    interim: Interim,
+) -> Result<ProcessCounts, Box<dyn std::error::Error>>
+where
+    S: StateManager<Err = std::io::Error> + Snapshot,
+{
+    let mapping = engine.format.mapping()?;
+    if engine.follow || engine.checkpoint.is_some() {
+        return feed_file(engine, &mapping, sources, session, state, feed, interim);
+    }
+
+    let mut feed = feed;
+    let several = sources.len() > 1;
+    let mut total = ProcessCounts::default();
+    for source in sources {
+        let origin = source.to_string();
+        let records = read_source(
+            engine.parse_threads,
+            engine.format.input_format,
+            &mapping,
+            source,
+        )?
+        .map(|record| match record {
+            Err(err) if several => Err(err.with_origin(origin.clone())),
+            record => record,
+        })
+        .take_while(|record| session.admit(record));
+        let counts = feed(state, Box::new(records));
+        if several {
+            eprintln!(
+                "{origin}: {} records, {} errors",
+                counts.records, counts.errors
+            );
+        }
+        total += counts;
+        if counts.stopped || session.done() {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+/// Feed the records of a single file into `state`, keeping track of the position reached.
+///
+/// With `--resume`, the state, counts, and position are first restored from the checkpoint file.
+/// With `--checkpoint`, the checkpoint file is written every `--checkpoint-every` records, and
+/// once the input ends. With `--follow`, the file is read as it grows until interrupted, and every
+/// `--checkpoint-interval` in which records arrived ends with the state handed to `interim`, and a
+/// checkpoint.
+///
+/// The counts returned include those restored from the checkpoint.
+fn feed_file<S>(
+    engine: &EngineArgs,
+    mapping: &InputMapping,
+    sources: &[InputSource],
+    session: &Session,
+    state: &mut S,
+    mut feed: impl FnMut(&mut S, Records<'_>) -> ProcessCounts,
+    interim: Interim,
+) -> Result<ProcessCounts, Box<dyn std::error::Error>>
+where
+    S: StateManager<Err = std::io::Error> + Snapshot,
+{
+    let path = match sources {
+        [InputSource::File(path)] => path,
+        _ => return Err("--follow and --checkpoint require exactly one input file".into()),
+    };
+    if engine.format.input_format != InputFormat::Csv {
+        return Err("--follow and --checkpoint require CSV input".into());
+    }
+    if Compression::of_file(path)? != Compression::None {
+        return Err("--follow and --checkpoint require an uncompressed input file".into());
+    }
+
+    let resumed = match &engine.checkpoint {
+        Some(checkpoint) if engine.resume && checkpoint.exists() => {
+            let resumed = Checkpoint::read(checkpoint, state)?;
+            // the last record may have lacked a newline, which is added as it is read, so the
+            // position may be one past the end of the file
+            let len = std::fs::metadata(path)?.len();
+            let byte = resumed.position.byte;
+            if len + 1 < byte {
+                return Err(format!(
+                    "{} is shorter than when it was checkpointed",
+                    path.display()
+                )
+                .into());
+            }
+            // if the file has grown since, that record may have been cut short, and the position
+            // would now be in the middle of its line
+            if byte > 0 && byte <= len && byte_before(path, byte)? != b'\n' {
+                return Err(format!(
+                    "{} has grown since its last line, which had no newline, was checkpointed",
+                    path.display()
+                )
+                .into());
+            }
+            eprintln!(
+                "resuming after record {} (line {})",
+                resumed.position.record,
+                resumed.position.line - 1
+            );
+            Some(resumed)
+        }
+        _ => None,
+    };
+    let start = resumed.map(|resumed| resumed.position);
+    let mut progress = resumed.unwrap_or(Checkpoint {
+        position: Position::START,
+        counts: ProcessCounts::default(),
+    });
+    let save = |state: &S, progress: &Checkpoint| match &engine.checkpoint {
+        Some(path) => progress.write(path, state),
+        None => Ok(()),
+    };
+
+    let input = std::fs::File::open(path)?;
+    let position = Cell::new(progress.position);
+    let track = |(at, record): (Position, Result<Event, ParseError>)| {
+        position.set(at);
+        record
+    };
+
+    if engine.follow {
+        let stop = Arc::new(AtomicBool::new(false));
+        let interrupted = Arc::clone(&stop);
+        ctrlc::set_handler(move || interrupted.store(true, Ordering::Relaxed))?;
+
+        let mut follower =
+            Follower::new(input, mapping.clone(), start, FollowConfig::default(), stop);
+        let interval = Duration::from_secs(engine.checkpoint_interval);
+        while !follower.is_finished() {
+            let records = follower
+                .until(Instant::now() + interval)
+                .take_while(|(_, record)| session.admit(record))
+                .map(track);
+            let counts = feed(state, Box::new(records));
+            progress.counts += counts;
+            progress.position = position.get();
+            if counts.stopped || session.done() {
+                break;
+            }
+            if counts.records > 0 {
+                save(state, &progress)?;
+                if !follower.is_finished() {
+                    interim(state)?;
+                }
+            }
+        }
+    } else {
+        let records: Box<dyn Iterator<Item = _>> = match start {
+            Some(start) => Box::new(resume_positioned_events(mapping, input, start)),
+            None => Box::new(read_positioned_events(mapping, input)),
+        };
+        let mut records = records
+            .take_while(|(_, record)| session.admit(record))
+            .map(track);
+        let every = engine.checkpoint_every.max(1);
+        loop {
+            let counts = feed(state, Box::new((&mut records).take(every)));
+            progress.counts += counts;
+            progress.position = position.get();
+            save(state, &progress)?;
+            if counts.records < every || counts.stopped || session.done() {
+                break;
+            }
+        }
+    }
+    Ok(progress.counts)
+}
+
+/// The byte of the file at `path` just before `offset`.
+fn byte_before(path: &Path, offset: u64) -> std::io::Result<u8> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset - 1))?;
+    let mut byte = [0];
+    file.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Process the inputs named by `engine`, then hand the resulting state to `then`.
+///
+/// Errors are reported to stderr as they arise if requested. While following an input, the state
+/// is handed to `interim` periodically.
+fn with_engine(
+    engine: &EngineArgs,
+    session: &Session,
+    interim: Interim,
+    then: impl FnOnce(State) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sources = expand_inputs(&engine.inputs)?;
+
+    let errors_to: Option<Box<dyn std::io::Write + Send>> = match &engine.errors_file {
+        Some(path) => Some(Box::new(std::io::BufWriter::new(std::fs::File::create(
+            path,
+        )?))),
+        // stderr is locked per error, so that progress reports can interleave
+        None if engine.debug => Some(Box::new(std::io::stderr())),
+        None => None,
+    };
+
+    let (errors, join_handle) = if let Some(errors_to) = errors_to {
         let (tx, rx) = std::sync::mpsc::sync_channel(16);
+        let format = engine.errors_format;
         (
             Some(tx),
-            Some(std::thread::spawn(move || {
-                use std::io::Write;
-
-                let stderr = std::io::stderr();
-                let mut stderr = stderr.lock();
-
+            Some(std::thread::spawn(move || -> std::io::Result<()> {
+                let mut writer = ErrorWriter::new(format, errors_to);
                 while let Ok(err) = rx.recv() {
-                    writeln!(stderr, "{err}").expect("writing to stderr never panics");
+                    writer.write(&err).map_err(std::io::Error::other)?;
                 }
+                writer.flush()
             })),
         )
     } else {
         (None, None)
     };
 
-    let mut state = MemoryState::default();
-    process_events(
-        &mut state,
-        reader
-            .into_deserialize()
-            .map(|maybe_event| maybe_event.expect("csv files are valid throughout")),
-        errors,
+    let result = match engine.layout {
+        Layout::Hash => {
+            run::<HashMap<ClientId, ClientState>>(engine, &sources, errors, session, interim, then)
+        }
+        Layout::Dense => run::<DenseClientTable>(engine, &sources, errors, session, interim, then),
+    };
+
+    // wait for all errors to be emitted before exiting
+    if let Some(handle) = join_handle {
+        handle.join().expect("error-display thread never panics")?;
+    }
+
+    // the export is completed even when processing fails, so that it can be read
+    let finished = session.finish();
+    result?;
+    finished
+}
+
+/// Process all sources into a state with the given client table, then hand it to `then`.
+///
+/// If too many records were malformed, processing is aborted and `then` is not called.
+fn run<Clients>(
+    engine: &EngineArgs,
+    sources: &[InputSource],
+    errors: Option<SyncSender<EventError<std::io::Error>>>,
+    session: &Session,
+    interim: Interim,
+    then: impl FnOnce(State) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    Clients: 'static + ClientTable + Default + Send,
+{
+    let errors = errors.as_ref();
+    let started = Instant::now();
+    let (counts, summary, deposit_stats): (_, _, DepositStats) = if engine.shards > 1 {
+        let mut state = ShardedState::new(engine.shards, || {
+            engine.make_state::<Clients>(session.history)
+        });
+        let mut counts = feed_sources(
+            engine,
+            sources,
+            session,
+            &mut state,
+            |state, records| feed_records_parallel(state, records, errors),
+            interim,
+        )?;
+        session.parse_errors.check()?;
+        counts.check_complete()?;
+        counts += report_finish(sources, finish_processing(&mut state, errors));
+        let summary = Summary::new(&counts, &state, started.elapsed());
+        then(&state)?;
+        let stats = state
+            .shards()
+            .iter()
+            .map(GenericMemoryState::deposit_stats)
+            .sum();
+        (counts, summary, stats)
+    } else {
+        let mut state = engine.make_state::<Clients>(session.history);
+        let mut counts = feed_sources(
+            engine,
+            sources,
+            session,
+            &mut state,
+            |state, records| feed_records(state, records, errors),
+            interim,
+        )?;
+        session.parse_errors.check()?;
+        counts.check_complete()?;
+        counts += report_finish(sources, finish_processing(&mut state, errors));
+        let summary = Summary::new(&counts, &state, started.elapsed());
+        then(&state)?;
+        (counts, summary, state.deposit_stats())
+    };
+
+    if engine.memory_report {
+        eprintln!("{deposit_stats}");
+    }
+    if engine.summary {
+        eprintln!("{summary}");
+    }
+    if let Some(path) = &engine.summary_file {
+        let mut file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(&mut file, &summary)?;
+        writeln!(file)?;
+    }
+
+    engine.check_thresholds(&counts)?;
+    Ok(())
+}
+
+/// Report errors which arose at the end of all inputs, alongside the per-source counts.
+fn report_finish(sources: &[InputSource], counts: ProcessCounts) -> ProcessCounts {
+    if sources.len() > 1 && counts.errors > 0 {
+        eprintln!("end of input: {} errors", counts.errors);
+    }
+    counts
+}
+
+/// Write the client state to the chosen output.
+///
+/// An output which is a regular file is replaced in one step, so that a reader never sees it
+/// partly written.
+fn write_state(output: &OutputArgs, state: State) -> Result<(), Box<dyn std::error::Error>> {
+    let clients = match output.sort.key() {
+        Some(key) => state.emit_sorted(key),
+        None => state.emit_state(),
+    };
+    write_output(output.output.as_deref(), |writer| {
+        Ok(write_clients(output.output_format, clients, writer)?)
+    })
+}
+
+/// Write to `path` with `write`, or to stdout if there is no path.
+///
+/// A path which is a regular file is replaced in one step, so that a reader never sees it partly
+/// written.
+fn write_output(
+    path: Option<&Path>,
+    write: impl FnOnce(&mut (dyn Write + Send)) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match path {
+        Some(path) if std::fs::metadata(path).map_or(true, |meta| meta.is_file()) => {
+            let mut name = path.file_name().unwrap_or_default().to_owned();
+            name.push(".partial");
+            let partial = path.with_file_name(name);
+            if let Err(err) = write(&mut std::fs::File::create(&partial)?) {
+                let _ = std::fs::remove_file(&partial);
+                return Err(err);
+            }
+            std::fs::rename(&partial, path)?;
+            Ok(())
+        }
+        Some(path) => write(&mut std::fs::File::create(path)?),
+        None => write(&mut std::io::stdout()),
+    }
+}
+
+/// Write a statement of the client's account over the requested range of events.
+fn statement(args: &StatementArgs, state: State) -> Result<(), Box<dyn std::error::Error>> {
+    if !state
+        .emit_state()
+        .any(|client| client.client == args.client)
+    {
+        return Err(format!("client {} does not exist", args.client).into());
+    }
+    let history = state
+        .history(args.client)
+        .ok_or("the state keeps no history")?;
+    let range = (
+        args.from_seq.map_or(Bound::Unbounded, Bound::Included),
+        args.to_seq.map_or(Bound::Unbounded, Bound::Included),
     );
+    let statement = Statement::new(args.client, history, range);
+    write_output(args.output.as_deref(), |writer| {
+        Ok(write_statement(args.format, &statement, writer)?)
+    })
+}
+
+/// Report every break between the computed and expected state, failing if there are any.
+fn reconcile_state(
+    args: &ReconcileArgs,
+    expected: BTreeMap<ClientId, SerializeClientState>,
+    state: State,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let reconciliation = reconcile(expected, state.emit_state());
+    write_output(args.output.as_deref(), |writer| {
+        Ok(write_reconciliation(args.report, &reconciliation, writer)?)
+    })?;
+    if reconciliation.is_clean() {
+        Ok(())
+    } else {
+        Err(Failure::new(
+            Failure::BREAK,
+            format!(
+                "{} breaks against {}",
+                reconciliation.breaks.len(),
+                args.expected.display()
+            ),
+        )
+        .into())
+    }
+}
 
-    let stdout = std::io::stdout();
-    let stdout = stdout.lock();
-    let mut writer = csv::Writer::from_writer(stdout);
+/// Report how each client changed between two sets of client state.
+fn diff_states(args: &DiffArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let read = |path: &Path| read_state(path).map_err(|err| format!("{}: {err}", path.display()));
+    let diff = diff(read(&args.before)?, read(&args.after)?);
+    write_output(args.output.as_deref(), |writer| {
+        Ok(write_diff(args.report, &diff, writer)?)
+    })
+}
 
-    for client_state in state.emit_state() {
-        writer.serialize(client_state)?;
+/// Rewrite every input as a single stream of events in another format.
+///
+/// Malformed records are reported to stderr and left out; the conversion then fails once the
+/// rest has been written.
+fn convert(args: &ConvertArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let sources = expand_inputs(&args.inputs)?;
+    let mapping = args.format.mapping()?;
+    let mut malformed = 0;
+    write_output(args.output.as_deref(), |writer| {
+        let mut writer = EventWriter::new(args.to, writer)?;
+        for source in &sources {
+            for record in read_source(0, args.format.input_format, &mapping, source)? {
+                match record {
+                    Ok(event) => writer.write(&event)?,
+                    Err(err) => {
+                        eprintln!("{}", err.with_origin(source.to_string()));
+                        malformed += 1;
+                    }
+                }
+            }
+        }
+        Ok(writer.flush()?)
+    })?;
+    if malformed > 0 {
+        Err(Failure::new(
+            Failure::PARSE,
+            format!("{malformed} malformed records were left out"),
+        )
+        .into())
+    } else {
+        Ok(())
     }
+}
 
-    // wait for all errors to be emitted before exiting
-    if let Some(handle) = join_handle {
-        handle.join().expect("error-display thread never panics");
+/// Parse and lint every input, reporting malformed records and findings.
+///
+/// Fails if any record is malformed, or any finding is an error.
+fn validate(args: &ValidateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let sources = expand_inputs(&args.inputs)?;
+    let mapping = args.format.mapping()?;
+    let text = args.report == ReportFormat::Text;
+    let mut linter = Linter::new();
+    for source in &sources {
+        for record in read_source(
+            args.parse_threads,
+            args.format.input_format,
+            &mapping,
+            source,
+        )? {
+            if let Err(err) = &record {
+                let err = err.clone().with_origin(source.to_string());
+                if text {
+                    println!("{err}");
+                } else {
+                    eprintln!("{err}");
+                }
+            }
+            for finding in linter.check_record(&record) {
+                if text {
+                    println!("{finding}");
+                }
+            }
+        }
     }
 
+    let report = linter.finish();
+    match args.report {
+        ReportFormat::Text => eprintln!(
+            "{} records: {} malformed, {} warnings, {} errors",
+            report.records, report.malformed, report.warnings, report.errors
+        ),
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(std::io::stdout().lock(), &report)?;
+            println!();
+        }
+        ReportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(std::io::stdout().lock());
+            for finding in &report.findings {
+                writer.serialize(finding)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    if report.malformed > 0 || report.errors > 0 {
+        let code = if report.errors > 0 {
+            Failure::REJECTED
+        } else {
+            Failure::PARSE
+        };
+        Err(Failure::new(
+            code,
+            format!(
+                "{} malformed records, {} errors",
+                report.malformed, report.errors
+            ),
+        )
+        .into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Write the state of `client`, and the events applied to them with their resulting balances, to
+/// stdout.
+fn inspect(client: ClientId, state: State) -> Result<(), Box<dyn std::error::Error>> {
+    let client_state = state
+        .emit_state()
+        .find(|client_state| client_state.client == client)
+        .ok_or_else(|| format!("client {client} does not exist"))?;
+    let history: Vec<_> = state
+        .history(client)
+        .ok_or("the state keeps no history")?
+        .collect();
+
+    let mut stdout = std::io::stdout();
+    write_clients(OutputFormat::Table, [client_state], &mut stdout)?;
+    writeln!(stdout, "\n{} events applied:", history.len())?;
+    write_history(history, &mut stdout)?;
     Ok(())
 }
@@ -0,0 +1,301 @@
+//! Mapping the CSV layouts of other producers onto events.
+//!
+//! Partners name their columns and event types differently, separate fields with other
+//! characters, or send no header row at all. An `InputMapping` describes such a layout, and is
+//! read from TOML or assembled from command line flags. It is compiled against the header row of
+//! each input into a `RecordMapping`, which renames the columns to the fields of `Event` and
+//! translates event type aliases before each record is deserialized.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    csv_reader_builder,
+    input::{parse_record, ParseError, RawRecord},
+    primitives::{Event, EventType},
+};
+
+/// The names of the source columns holding each field of an `Event`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Columns {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub client: String,
+    pub tx: String,
+    pub amount: String,
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Columns {
+            event_type: "type".into(),
+            client: "client".into(),
+            tx: "tx".into(),
+            amount: "amount".into(),
+        }
+    }
+}
+
+impl Columns {
+    /// Each source column, paired with the name of the field it holds.
+    fn fields(&self) -> [(&str, &'static str); 4] {
+        [
+            (&self.event_type, "type"),
+            (&self.client, "client"),
+            (&self.tx, "tx"),
+            (&self.amount, "amount"),
+        ]
+    }
+}
+
+/// InputMapping describes the layout of a CSV input.
+///
+/// The default describes the layout this program has always read: a header row naming the
+/// columns `type`, `client`, `tx`, and `amount`, separated by commas.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct InputMapping {
+    /// The source column holding each field. Columns not named here are ignored.
+    pub columns: Columns,
+    /// Alternative names for event types, such as `DEP` for `deposit`.
+    ///
+    /// The usual names are accepted as well.
+    pub event_types: BTreeMap<String, EventType>,
+    /// The character separating fields.
+    #[serde(deserialize_with = "ascii_byte")]
+    pub delimiter: u8,
+    /// The character quoting fields.
+    #[serde(deserialize_with = "ascii_byte")]
+    pub quote: u8,
+    /// The names of the columns, in order, for input without a header row.
+    ///
+    /// When this is `None`, the first row of the input is its header.
+    pub header: Option<Vec<String>>,
+}
+
+impl Default for InputMapping {
+    fn default() -> Self {
+        InputMapping {
+            columns: Columns::default(),
+            event_types: BTreeMap::new(),
+            delimiter: b',',
+            quote: b'"',
+            header: None,
+        }
+    }
+}
+
+fn ascii_byte<'de, D>(de: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let c = char::deserialize(de)?;
+    u8::try_from(c)
+        .ok()
+        .filter(u8::is_ascii)
+        .ok_or_else(|| serde::de::Error::custom(format!("`{c}` is not an ASCII character")))
+}
+
+impl InputMapping {
+    /// Read a mapping from TOML. Anything not given keeps its default.
+    ///
+    /// ```toml
+    /// delimiter = ";"
+    ///
+    /// [columns]
+    /// type = "kind"
+    /// client = "account"
+    /// tx = "ref"
+    /// amount = "value"
+    ///
+    /// [event-types]
+    /// DEP = "deposit"
+    /// WD = "withdrawal"
+    /// CB = "chargeback"
+    /// ```
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Construct a CSV reader configured for input with this layout.
+    ///
+    /// Like `csv_reader_builder`, fields are trimmed, `#` begins a comment, and the reader is
+    /// flexible about the number of fields per record.
+    pub fn reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv_reader_builder();
+        builder
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .has_headers(self.header.is_none());
+        builder
+    }
+
+    /// The header row given by this mapping, for input without one of its own.
+    pub fn header_record(&self) -> Option<csv::ByteRecord> {
+        self.header
+            .as_ref()
+            .map(|names| names.iter().map(|name| name.trim()).collect())
+    }
+
+    /// Compile this mapping against the header row of an input.
+    pub fn compile(&self, headers: &csv::ByteRecord) -> RecordMapping {
+        let fields = self.columns.fields();
+        let headers = headers
+            .iter()
+            .map(|name| {
+                fields
+                    .iter()
+                    .find(|(column, _)| column.as_bytes() == name)
+                    .map_or("", |(_, field)| *field)
+            })
+            .collect::<csv::ByteRecord>();
+        let type_column = if self.event_types.is_empty() {
+            None
+        } else {
+            headers.iter().position(|name| name == b"type")
+        };
+        let event_types = self
+            .event_types
+            .iter()
+            .map(|(alias, event_type)| (alias.as_bytes().to_vec(), event_type.name()))
+            .collect();
+        RecordMapping {
+            headers,
+            type_column,
+            event_types,
+        }
+    }
+}
+
+/// RecordMapping turns the records of one input into events, according to an `InputMapping`.
+#[derive(Debug, Clone)]
+pub struct RecordMapping {
+    /// The header row, with each mapped column renamed to its field, and every other column
+    /// renamed to the empty string.
+    headers: csv::ByteRecord,
+    /// The position of the event type column, if there are aliases to translate in it.
+    type_column: Option<usize>,
+    event_types: BTreeMap<Vec<u8>, &'static str>,
+}
+
+impl RecordMapping {
+    /// Deserialize a single record into an `Event`, as `parse_record` does.
+    ///
+    /// A `ParseError` carries the text of the record as it appeared in the input, before any
+    /// event type alias was translated.
+    pub fn parse(&self, record: &csv::ByteRecord, raw: RawRecord) -> Result<Event, ParseError> {
+        let alias = self.type_column.and_then(|column| {
+            let event_type = self.event_types.get(record.get(column)?)?;
+            Some((column, event_type))
+        });
+        let (column, event_type) = match alias {
+            Some(alias) => alias,
+            None => return parse_record(&self.headers, record, raw),
+        };
+        let translated = record
+            .iter()
+            .enumerate()
+            .map(|(index, field)| {
+                if index == column {
+                    event_type.as_bytes()
+                } else {
+                    field
+                }
+            })
+            .collect::<csv::ByteRecord>();
+        parse_record(&self.headers, &translated, raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::read_events;
+
+    fn amount(amount: &str) -> crate::primitives::Amount {
+        amount.parse().expect("test amounts are valid")
+    }
+
+    #[test]
+    fn partner_layouts_read_as_events() {
+        let mapping = InputMapping::from_toml(
+            r#"
+            delimiter = ";"
+            quote = "'"
+
+            [columns]
+            type = "kind"
+            client = "account"
+            tx = "ref"
+            amount = "value"
+
+            [event-types]
+            DEP = "deposit"
+            WD = "withdrawal"
+            CB = "chargeback"
+            "#,
+        )
+        .expect("mapping is valid");
+        let data = "\
+ref; note; account; kind; value
+1;'first; of many';1;DEP;2.0
+2; ; 1; WD; 0.5
+1; ; 1; dispute;
+1; ; 1; CB;
+3; ; 1; FEE; 1.0
+";
+        let results: Vec<_> = read_events(&mapping, data.as_bytes()).collect();
+
+        assert_eq!(results.len(), 5);
+        let event = |index: usize| results[index].as_ref().expect("event is valid");
+        assert_eq!(event(0).event_type, EventType::Deposit);
+        assert_eq!(event(0).amount, amount("2"));
+        assert_eq!(event(1).event_type, EventType::Withdrawal);
+        assert_eq!(event(2).event_type, EventType::Dispute);
+        assert_eq!(event(3).event_type, EventType::Chargeback);
+        let err = results[4].as_ref().expect_err("FEE is not an event type");
+        assert_eq!(err.line, 6);
+        assert_eq!(err.text, "3; ; 1; FEE; 1.0");
+    }
+
+    #[test]
+    fn header_less_input_reads_every_row() {
+        let mapping = InputMapping {
+            header: Some(vec![
+                "kind".into(),
+                "account".into(),
+                "ref".into(),
+                "value".into(),
+            ]),
+            columns: Columns {
+                event_type: "kind".into(),
+                client: "account".into(),
+                tx: "ref".into(),
+                amount: "value".into(),
+            },
+            ..InputMapping::default()
+        };
+        let data = "deposit,1,1,1.0\nwithdrawal,1,2,0.5\n";
+        let results: Vec<_> = read_events(&mapping, data.as_bytes()).collect();
+
+        let txs: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().expect("event is valid").tx)
+            .collect();
+        assert_eq!(txs, [1.into(), 2.into()]);
+    }
+
+    #[test]
+    fn mappings_reject_what_they_cannot_use() {
+        assert!(InputMapping::from_toml(r#"delimiter = "→""#).is_err());
+        assert!(InputMapping::from_toml(r#"delimiter = ";;""#).is_err());
+        assert!(InputMapping::from_toml("[event-types]\nDEP = \"deposits\"").is_err());
+        assert!(InputMapping::from_toml("[columns]\nkind = \"type\"").is_err());
+        assert_eq!(
+            InputMapping::from_toml("").expect("an empty mapping is valid"),
+            InputMapping::default()
+        );
+    }
+}
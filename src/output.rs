@@ -0,0 +1,884 @@
+//! Writing client state and errors in a choice of formats.
+//!
+//! Every client state format consumes the iterator produced by `StateManager::emit_state`. CSV,
+//! JSON, and NDJSON stream each client as it arrives; only the aligned table must see every
+//! client before writing, in order to size its columns.
+//!
+//! Errors are written one at a time, as they arise, by an `ErrorWriter`, and events are written by
+//! an `EventWriter` in any of the formats they can be read from.
+
+use std::io::{self, BufWriter, Write};
+
+use serde::Serialize;
+
+use crate::{
+    binary,
+    diff::{ClientChange, LockChange, Movement, Presence, StateDiff},
+    input::InputFormat,
+    primitives::{
+        Amount, AmountDelta, ClientId, Event, EventType, SerializeClientState, TransactionId,
+    },
+    reconcile::{Break, Reconciliation},
+    state::HistoryEntry,
+    statement::Statement,
+    EventError,
+};
+
+/// The format in which client state is written.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Comma-separated values with a header row.
+    Csv,
+    /// A single JSON array of client objects.
+    Json,
+    /// One JSON object per line.
+    Ndjson,
+    /// A table aligned for reading in a terminal.
+    Table,
+    /// The compact encoding of the `binary` module.
+    Binary,
+    /// A Parquet file with decimal amount columns, as written by the `columnar` module.
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OutputError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "parquet")]
+    #[error(transparent)]
+    Parquet(#[from] parquet::errors::ParquetError),
+}
+
+/// Write each client to `writer` in the given format.
+pub fn write_clients<W, I>(format: OutputFormat, clients: I, writer: W) -> Result<(), OutputError>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = SerializeClientState>,
+{
+    let mut writer = BufWriter::new(writer);
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut writer);
+            for client in clients {
+                writer.serialize(client)?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Json => {
+            let mut separator = "[\n  ";
+            for client in clients {
+                writer.write_all(separator.as_bytes())?;
+                serde_json::to_writer(&mut writer, &client)?;
+                separator = ",\n  ";
+            }
+            if separator.starts_with('[') {
+                // no clients were written
+                writer.write_all(b"[")?;
+            } else {
+                writer.write_all(b"\n")?;
+            }
+            writer.write_all(b"]\n")?;
+        }
+        OutputFormat::Ndjson => {
+            for client in clients {
+                serde_json::to_writer(&mut writer, &client)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        OutputFormat::Table => write_table(clients, &mut writer)?,
+        OutputFormat::Binary => {
+            let mut writer = binary::Writer::new(&mut writer)?;
+            for client in clients {
+                writer.write(&client)?;
+            }
+            writer.finish()?;
+        }
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => {
+            crate::columnar::write_clients(clients, &mut writer)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Format an amount with all four decimal places, so that decimal points align.
+pub fn fixed_point(amount: Amount) -> String {
+    let raw = amount.to_raw();
+    format!("{}.{:04}", raw / 10_000, raw % 10_000)
+}
+
+/// Format a delta with its sign and all four decimal places, so that decimal points align.
+pub fn signed_fixed_point(delta: AmountDelta) -> String {
+    let raw = delta.to_raw();
+    let sign = if raw < 0 { "-" } else { "+" };
+    let raw = raw.unsigned_abs();
+    format!("{sign}{}.{:04}", raw / 10_000, raw % 10_000)
+}
+
+const TABLE_HEADER: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+/// Write clients as a table: numbers are right-aligned, and columns are separated by two spaces.
+fn write_table<W, I>(clients: I, writer: &mut W) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = SerializeClientState>,
+{
+    let rows: Vec<[String; 5]> = clients
+        .into_iter()
+        .map(|client| {
+            [
+                client.client.to_string(),
+                fixed_point(client.available),
+                fixed_point(client.held),
+                fixed_point(client.total),
+                client.locked.to_string(),
+            ]
+        })
+        .collect();
+    write_rows(TABLE_HEADER, &rows, writer)
+}
+
+const HISTORY_HEADER: [&str; 8] = [
+    "seq",
+    "type",
+    "tx",
+    "amount",
+    "available",
+    "held",
+    "total",
+    "locked",
+];
+
+/// Write the history of a client as a table: each event, followed by the client's balances once
+/// it was applied.
+pub fn write_history<W, I>(entries: I, writer: &mut W) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = HistoryEntry>,
+{
+    let rows: Vec<_> = entries
+        .into_iter()
+        .map(|entry| history_row(&entry))
+        .collect();
+    write_rows(HISTORY_HEADER, &rows, writer)
+}
+
+fn history_row(entry: &HistoryEntry) -> [String; 8] {
+    let event = &entry.event;
+    let amount = if event.has_amount() {
+        fixed_point(event.amount)
+    } else {
+        String::new()
+    };
+    let [.., available, held, total, locked] = balance_row("", &entry.state);
+    [
+        entry.seq.to_string(),
+        event.event_type.to_string(),
+        event.tx.to_string(),
+        amount,
+        available,
+        held,
+        total,
+        locked,
+    ]
+}
+
+/// A row of the history table holding only balances, labelled in place of an event type.
+fn balance_row(label: &str, state: &SerializeClientState) -> [String; 8] {
+    [
+        String::new(),
+        label.to_owned(),
+        String::new(),
+        String::new(),
+        fixed_point(state.available),
+        fixed_point(state.held),
+        fixed_point(state.total),
+        state.locked.to_string(),
+    ]
+}
+
+/// The format in which statements are written.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatementFormat {
+    /// A title line, followed by an aligned table.
+    Text,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+/// Write a statement: a row of opening balances, a row for each event with the balances after
+/// it, and a row of closing balances. Every amount has all four decimal places.
+pub fn write_statement<W: Write + ?Sized>(
+    format: StatementFormat,
+    statement: &Statement,
+    writer: &mut W,
+) -> Result<(), OutputError> {
+    let rows: Vec<_> = std::iter::once(balance_row("opening", &statement.opening))
+        .chain(statement.entries.iter().map(history_row))
+        .chain(std::iter::once(balance_row("closing", &statement.closing)))
+        .collect();
+    match format {
+        StatementFormat::Text => {
+            writeln!(
+                writer,
+                "Statement for client {}: {} events",
+                statement.client,
+                statement.entries.len()
+            )?;
+            write_rows(HISTORY_HEADER, &rows, writer)?;
+        }
+        StatementFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(HISTORY_HEADER)?;
+            for row in &rows {
+                writer.write_record(row)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// The format in which reconciliation reports are written.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconcileFormat {
+    /// A summary line, followed by an aligned table of any breaks.
+    Text,
+    /// Comma-separated values with a header row, one row per break.
+    Csv,
+}
+
+const BREAK_HEADER: [&str; 6] = [
+    "client",
+    "break",
+    "field",
+    "expected",
+    "actual",
+    "difference",
+];
+
+/// Write the breaks found by a reconciliation. A missing or extra client is a single row, with
+/// its fields left empty; every other break names the field which differs.
+pub fn write_reconciliation<W: Write + ?Sized>(
+    format: ReconcileFormat,
+    reconciliation: &Reconciliation,
+    writer: &mut W,
+) -> Result<(), OutputError> {
+    let rows: Vec<_> = reconciliation.breaks.iter().map(break_row).collect();
+    match format {
+        ReconcileFormat::Text => {
+            writeln!(
+                writer,
+                "{} clients matched, {} breaks",
+                reconciliation.matched,
+                rows.len()
+            )?;
+            if !rows.is_empty() {
+                write_rows(BREAK_HEADER, &rows, writer)?;
+            }
+        }
+        ReconcileFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(BREAK_HEADER)?;
+            for row in &rows {
+                writer.write_record(row)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn break_row(found: &Break) -> [String; 6] {
+    let client = found.client().to_string();
+    let empty = String::new;
+    match found {
+        Break::Missing(_) => [client, "missing".into(), empty(), empty(), empty(), empty()],
+        Break::Extra(_) => [client, "extra".into(), empty(), empty(), empty(), empty()],
+        Break::Amount {
+            field,
+            expected,
+            actual,
+            ..
+        } => [
+            client,
+            "mismatch".into(),
+            field.to_string(),
+            fixed_point(*expected),
+            fixed_point(*actual),
+            signed_fixed_point(AmountDelta::between(*expected, *actual)),
+        ],
+        Break::Locked {
+            expected, actual, ..
+        } => [
+            client,
+            "mismatch".into(),
+            "locked".into(),
+            expected.to_string(),
+            actual.to_string(),
+            empty(),
+        ],
+    }
+}
+
+/// The format in which diffs of client state are written.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffFormat {
+    /// A summary line, followed by an aligned table of changed clients and their total movement.
+    Text,
+    /// Comma-separated values with a header row, one row per changed client and a final row of
+    /// their total movement.
+    Csv,
+}
+
+const DIFF_HEADER: [&str; 6] = ["client", "change", "available", "held", "total", "lock"];
+
+/// Write a diff of client state: a row for each changed client, with the change in each amount and
+/// any change of lock, and a row of the movement of every client together.
+pub fn write_diff<W: Write + ?Sized>(
+    format: DiffFormat,
+    diff: &StateDiff,
+    writer: &mut W,
+) -> Result<(), OutputError> {
+    let rows: Vec<_> = diff
+        .changes
+        .iter()
+        .map(change_row)
+        .chain(std::iter::once(movement_row(
+            String::new(),
+            "all",
+            &diff.movement,
+            "",
+        )))
+        .collect();
+    match format {
+        DiffFormat::Text => {
+            writeln!(
+                writer,
+                "{} clients changed, {} unchanged: {} added, {} removed, {} locked, {} unlocked",
+                diff.changes.len(),
+                diff.unchanged,
+                diff.count(Presence::Added),
+                diff.count(Presence::Removed),
+                diff.lock_changes(LockChange::Locked).count(),
+                diff.lock_changes(LockChange::Unlocked).count(),
+            )?;
+            write_rows(DIFF_HEADER, &rows, writer)?;
+        }
+        DiffFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(DIFF_HEADER)?;
+            for row in &rows {
+                writer.write_record(row)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn change_row(change: &ClientChange) -> [String; 6] {
+    movement_row(
+        change.client.to_string(),
+        change.presence.name(),
+        &change.movement,
+        change.lock.map_or("", LockChange::name),
+    )
+}
+
+fn movement_row(client: String, label: &str, movement: &Movement, lock: &str) -> [String; 6] {
+    [
+        client,
+        label.into(),
+        signed_fixed_point(movement.available),
+        signed_fixed_point(movement.held),
+        signed_fixed_point(movement.total),
+        lock.into(),
+    ]
+}
+
+/// Write rows beneath a header, right-aligning every column but the last, which is left-aligned.
+/// Columns are separated by two spaces.
+fn write_rows<W, const N: usize>(
+    header: [&str; N],
+    rows: &[[String; N]],
+    writer: &mut W,
+) -> io::Result<()>
+where
+    W: Write + ?Sized,
+{
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let header = header.map(String::from);
+    for row in std::iter::once(&header).chain(rows) {
+        let mut line = String::new();
+        for (idx, (cell, width)) in row.iter().zip(widths).enumerate() {
+            if idx > 0 {
+                line.push_str("  ");
+            }
+            if idx == row.len() - 1 {
+                line.push_str(&format!("{cell:<width$}"));
+            } else {
+                line.push_str(&format!("{cell:>width$}"));
+            }
+        }
+        writeln!(writer, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// The format in which errors are written.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// One human-readable message per line.
+    Text,
+    /// One JSON object per line, carrying client, tx, code, reason, and message.
+    Json,
+    /// Comma-separated values with a header row, carrying the same fields as `json`.
+    Csv,
+}
+
+/// ErrorWriter writes a stream of errors in a given format.
+pub struct ErrorWriter<W: Write> {
+    format: ErrorFormat,
+    writer: ErrorSink<W>,
+}
+
+enum ErrorSink<W: Write> {
+    Plain(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> ErrorWriter<W> {
+    pub fn new(format: ErrorFormat, writer: W) -> Self {
+        let writer = match format {
+            ErrorFormat::Text | ErrorFormat::Json => ErrorSink::Plain(writer),
+            ErrorFormat::Csv => ErrorSink::Csv(Box::new(csv::Writer::from_writer(writer))),
+        };
+        ErrorWriter { format, writer }
+    }
+
+    pub fn write<E>(&mut self, err: &EventError<E>) -> Result<(), OutputError>
+    where
+        E: std::fmt::Display,
+    {
+        match (&mut self.writer, self.format) {
+            (ErrorSink::Plain(writer), ErrorFormat::Json) => {
+                // a single write, so that a record on stderr is not interleaved with other output
+                let mut line = serde_json::to_vec(err)?;
+                line.push(b'\n');
+                writer.write_all(&line)?;
+            }
+            (ErrorSink::Plain(writer), _) => writeln!(writer, "{err}")?,
+            (ErrorSink::Csv(writer), _) => {
+                writer.serialize(err)?;
+                // errors are rare and should be seen promptly
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            ErrorSink::Plain(writer) => writer.flush(),
+            ErrorSink::Csv(writer) => writer.flush(),
+        }
+    }
+}
+
+/// EventWriter writes a stream of events in a format they can be read back from.
+///
+/// Amounts are written exactly: as decimal text in CSV and NDJSON, and as their raw value in
+/// binary. The amount of an event which carries none is left out, unless the input gave one.
+pub struct EventWriter<W: Write> {
+    writer: EventSink<W>,
+}
+
+enum EventSink<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Ndjson(BufWriter<W>),
+    Binary(binary::Writer<W, Event>),
+}
+
+/// An event as written to NDJSON, with its amount as a string so that it is not rounded.
+#[derive(Serialize)]
+struct JsonEvent {
+    #[serde(rename = "type")]
+    event_type: EventType,
+    client: ClientId,
+    tx: TransactionId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount: Option<String>,
+}
+
+/// The amount of an event as text, if it carries one.
+fn amount_text(event: &Event) -> Option<String> {
+    (event.has_amount() || !event.amount.is_zero()).then(|| event.amount.to_string())
+}
+
+impl<W: Write> EventWriter<W> {
+    /// Begin writing events, with a header row or stream header if the format has one.
+    pub fn new(format: InputFormat, writer: W) -> Result<Self, OutputError> {
+        let writer = match format {
+            InputFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                writer.write_record(["type", "client", "tx", "amount"])?;
+                EventSink::Csv(Box::new(writer))
+            }
+            InputFormat::Ndjson => EventSink::Ndjson(BufWriter::new(writer)),
+            InputFormat::Binary => EventSink::Binary(binary::Writer::new(writer)?),
+        };
+        Ok(EventWriter { writer })
+    }
+
+    pub fn write(&mut self, event: &Event) -> Result<(), OutputError> {
+        match &mut self.writer {
+            EventSink::Csv(writer) => writer.write_record([
+                event.event_type.name(),
+                &event.client.to_string(),
+                &event.tx.to_string(),
+                &amount_text(event).unwrap_or_default(),
+            ])?,
+            EventSink::Ndjson(writer) => {
+                let event = JsonEvent {
+                    event_type: event.event_type,
+                    client: event.client,
+                    tx: event.tx,
+                    amount: amount_text(event),
+                };
+                serde_json::to_writer(&mut *writer, &event)?;
+                writer.write_all(b"\n")?;
+            }
+            EventSink::Binary(writer) => writer.write(event)?,
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            EventSink::Csv(writer) => writer.flush(),
+            EventSink::Ndjson(writer) => writer.flush(),
+            EventSink::Binary(writer) => writer.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        input::{read_binary_events, read_events, read_ndjson_events},
+        mapping::InputMapping,
+        primitives::{tests::arb_event, ClientState},
+    };
+    use proptest::prelude::*;
+
+    fn clients() -> Vec<SerializeClientState> {
+        vec![
+            ClientState {
+                available: "1.5".parse().expect("valid amount"),
+                held: "10".parse().expect("valid amount"),
+                locked: false,
+            }
+            .to_serialize(1.into()),
+            ClientState {
+                available: "0.0001".parse().expect("valid amount"),
+                held: Default::default(),
+                locked: true,
+            }
+            .to_serialize(65535.into()),
+        ]
+    }
+
+    fn written(format: OutputFormat, clients: Vec<SerializeClientState>) -> String {
+        let mut out = Vec::new();
+        write_clients(format, clients, &mut out).expect("writing to a vec succeeds");
+        String::from_utf8(out).expect("output is utf-8")
+    }
+
+    #[test]
+    fn csv_output() {
+        assert_eq!(
+            written(OutputFormat::Csv, clients()),
+            "client,available,held,total,locked\n1,1.5,10.0,11.5,false\n65535,0.0001,0.0,0.0001,true\n",
+        );
+    }
+
+    #[test]
+    fn json_formats_agree() {
+        let json: serde_json::Value =
+            serde_json::from_str(&written(OutputFormat::Json, clients())).expect("valid json");
+        let ndjson: Vec<serde_json::Value> = written(OutputFormat::Ndjson, clients())
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("each line is valid json"))
+            .collect();
+        assert_eq!(json, serde_json::Value::Array(ndjson));
+        assert_eq!(json[0]["total"], 11.5);
+        assert_eq!(json[1]["locked"], true);
+
+        let empty: serde_json::Value =
+            serde_json::from_str(&written(OutputFormat::Json, Vec::new())).expect("valid json");
+        assert_eq!(empty, serde_json::json!([]));
+        assert_eq!(written(OutputFormat::Ndjson, Vec::new()), "");
+    }
+
+    fn errors() -> Vec<EventError<io::Error>> {
+        vec![
+            EventError::InsufficientFunds(2.into(), 5.into()),
+            EventError::DuplicateTransactionId(3.into(), 1.into()),
+            EventError::UnknownClient(4.into(), 7.into()),
+        ]
+    }
+
+    fn written_errors(format: ErrorFormat) -> String {
+        let mut out = Vec::new();
+        let mut writer = ErrorWriter::new(format, &mut out);
+        for err in errors() {
+            writer.write(&err).expect("writing to a vec succeeds");
+        }
+        writer.flush().expect("flushing a vec succeeds");
+        drop(writer);
+        String::from_utf8(out).expect("output is utf-8")
+    }
+
+    #[test]
+    fn error_formats() {
+        assert_eq!(
+            written_errors(ErrorFormat::Csv),
+            concat!(
+                "client,tx,code,reason,message\n",
+                "2,5,2,insufficient-funds,client 2 has insufficient funds to withdraw as requested by transaction 5\n",
+                "3,1,1,duplicate-transaction-id,transaction 1 already exists; IDs may not be duplicated\n",
+                "4,7,5,unknown-client,client 4 does not exist\n",
+            ),
+        );
+
+        let json: Vec<serde_json::Value> = written_errors(ErrorFormat::Json)
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("each line is valid json"))
+            .collect();
+        assert_eq!(json[0]["reason"], "insufficient-funds");
+        assert_eq!(json[0]["code"], 2);
+        assert_eq!(json[1]["client"], 3);
+        assert_eq!(json[1]["tx"], 1);
+        assert_eq!(json[2]["client"], 4);
+        assert_eq!(json[2]["tx"], 7);
+
+        let text = written_errors(ErrorFormat::Text);
+        assert_eq!(text.lines().count(), 3);
+    }
+
+    fn written_events(format: InputFormat, events: &[Event]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = EventWriter::new(format, &mut out).expect("writing to a vec succeeds");
+        for event in events {
+            writer.write(event).expect("writing to a vec succeeds");
+        }
+        writer.flush().expect("flushing a vec succeeds");
+        drop(writer);
+        out
+    }
+
+    proptest! {
+        #[test]
+        fn events_convert_between_formats(events in proptest::collection::vec(arb_event(u16::MAX, 1e12), 0..50)) {
+            for format in [InputFormat::Csv, InputFormat::Ndjson, InputFormat::Binary] {
+                let bytes = written_events(format, &events);
+                let read: Result<Vec<Event>, _> = match format {
+                    InputFormat::Csv => read_events(&InputMapping::default(), bytes.as_slice()).collect(),
+                    InputFormat::Ndjson => read_ndjson_events(bytes.as_slice()).collect(),
+                    InputFormat::Binary => read_binary_events(bytes.as_slice())
+                        .expect("header is valid")
+                        .collect(),
+                };
+                prop_assert_eq!(read.expect("every event reads back"), events.clone(), "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn events_are_written_as_they_are_read() {
+        let events: Vec<Event> = read_events(
+            &InputMapping::default(),
+            "type,client,tx,amount\ndeposit,1,1,0.57\ndispute,1,1,\n".as_bytes(),
+        )
+        .collect::<Result<_, _>>()
+        .expect("events are valid");
+        assert_eq!(
+            String::from_utf8(written_events(InputFormat::Csv, &events)).expect("output is utf-8"),
+            "type,client,tx,amount\ndeposit,1,1,0.5700\ndispute,1,1,\n",
+        );
+        assert_eq!(
+            String::from_utf8(written_events(InputFormat::Ndjson, &events))
+                .expect("output is utf-8"),
+            concat!(
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"0.5700"}"#,
+                "\n",
+                r#"{"type":"dispute","client":1,"tx":1}"#,
+                "\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn binary_output_reads_back() {
+        let mut out = Vec::new();
+        write_clients(OutputFormat::Binary, clients(), &mut out)
+            .expect("writing to a vec succeeds");
+        let read: Vec<SerializeClientState> = binary::Reader::new(out.as_slice())
+            .expect("header is valid")
+            .collect::<Result<_, _>>()
+            .expect("records are valid");
+        assert_eq!(read, clients());
+    }
+
+    fn statement() -> Statement {
+        let mut state = crate::state::memory::MemoryState::default().with_history();
+        let events = read_events(
+            &InputMapping::default(),
+            "type,client,tx,amount\ndeposit,1,1,2\ndeposit,1,2,1\nwithdrawal,1,3,0.5\ndispute,1,2,\n"
+                .as_bytes(),
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .expect("events are valid");
+        crate::process_events(&mut state, events, None);
+        let history =
+            crate::state::StateManager::history(&state, 1.into()).expect("history is kept");
+        Statement::new(1.into(), history, 3..)
+    }
+
+    fn written_statement(format: StatementFormat) -> String {
+        let mut out = Vec::new();
+        write_statement(format, &statement(), &mut out).expect("writing to a vec succeeds");
+        String::from_utf8(out).expect("output is utf-8")
+    }
+
+    #[test]
+    fn statement_formats() {
+        assert_eq!(
+            written_statement(StatementFormat::Csv),
+            concat!(
+                "seq,type,tx,amount,available,held,total,locked\n",
+                ",opening,,,3.0000,0.0000,3.0000,false\n",
+                "3,withdrawal,3,0.5000,2.5000,0.0000,2.5000,false\n",
+                "4,dispute,2,,1.5000,1.0000,2.5000,false\n",
+                ",closing,,,1.5000,1.0000,2.5000,false\n",
+            ),
+        );
+        assert_eq!(
+            written_statement(StatementFormat::Text),
+            concat!(
+                "Statement for client 1: 2 events\n",
+                "seq        type  tx  amount  available    held   total  locked\n",
+                "        opening                 3.0000  0.0000  3.0000  false\n",
+                "  3  withdrawal   3  0.5000     2.5000  0.0000  2.5000  false\n",
+                "  4     dispute   2             1.5000  1.0000  2.5000  false\n",
+                "        closing                 1.5000  1.0000  2.5000  false\n",
+            ),
+        );
+    }
+
+    fn written_reconciliation(format: ReconcileFormat) -> String {
+        let read = |csv: &str| {
+            let csv = format!("client,available,held,total,locked\n{csv}");
+            crate::reconcile::read_clients(csv.as_bytes()).expect("client state is valid")
+        };
+        let expected = read("1,1.5,0,1.5,false\n2,0,0,0,false\n3,1,0,1,false\n5,1,1,2,false\n");
+        let actual = read("1,1.5,10,11.5,false\n2,0,0,0,true\n4,2,0,2,false\n5,1,1,2,false\n");
+        let reconciliation = crate::reconcile::reconcile(expected, actual.into_values());
+        let mut out = Vec::new();
+        write_reconciliation(format, &reconciliation, &mut out).expect("writing to a vec succeeds");
+        String::from_utf8(out).expect("output is utf-8")
+    }
+
+    #[test]
+    fn reconciliation_formats() {
+        assert_eq!(
+            written_reconciliation(ReconcileFormat::Csv),
+            concat!(
+                "client,break,field,expected,actual,difference\n",
+                "1,mismatch,held,0.0000,10.0000,+10.0000\n",
+                "1,mismatch,total,1.5000,11.5000,+10.0000\n",
+                "2,mismatch,locked,false,true,\n",
+                "3,missing,,,,\n",
+                "4,extra,,,,\n",
+            ),
+        );
+        assert_eq!(
+            written_reconciliation(ReconcileFormat::Text),
+            concat!(
+                "1 clients matched, 5 breaks\n",
+                "client     break   field  expected   actual  difference\n",
+                "     1  mismatch    held    0.0000  10.0000  +10.0000\n",
+                "     1  mismatch   total    1.5000  11.5000  +10.0000\n",
+                "     2  mismatch  locked     false     true\n",
+                "     3   missing\n",
+                "     4     extra\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn diff_formats() {
+        let read = |csv: &str| {
+            let csv = format!("client,available,held,total,locked\n{csv}");
+            crate::reconcile::read_clients(csv.as_bytes()).expect("client state is valid")
+        };
+        let before = read("1,1.5,0,1.5,false\n2,0,0,0,false\n3,1,0,1,false\n");
+        let after = read("1,1.5,10,11.5,false\n2,0,0,0,true\n4,2,0,2,false\n");
+        let diff = crate::diff::diff(before.into_values(), after.into_values());
+        let written = |format| {
+            let mut out = Vec::new();
+            write_diff(format, &diff, &mut out).expect("writing to a vec succeeds");
+            String::from_utf8(out).expect("output is utf-8")
+        };
+        assert_eq!(
+            written(DiffFormat::Csv),
+            concat!(
+                "client,change,available,held,total,lock\n",
+                "1,changed,+0.0000,+10.0000,+10.0000,\n",
+                "2,changed,+0.0000,+0.0000,+0.0000,locked\n",
+                "3,removed,-1.0000,+0.0000,-1.0000,\n",
+                "4,added,+2.0000,+0.0000,+2.0000,\n",
+                ",all,+1.0000,+10.0000,+11.0000,\n",
+            ),
+        );
+        assert_eq!(
+            written(DiffFormat::Text),
+            concat!(
+                "4 clients changed, 0 unchanged: 1 added, 1 removed, 1 locked, 0 unlocked\n",
+                "client   change  available      held     total  lock\n",
+                "     1  changed    +0.0000  +10.0000  +10.0000\n",
+                "     2  changed    +0.0000   +0.0000   +0.0000  locked\n",
+                "     3  removed    -1.0000   +0.0000   -1.0000\n",
+                "     4    added    +2.0000   +0.0000   +2.0000\n",
+                "            all    +1.0000  +10.0000  +11.0000\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn table_output_is_aligned() {
+        assert_eq!(
+            written(OutputFormat::Table, clients()),
+            concat!(
+                "client  available     held    total  locked\n",
+                "     1     1.5000  10.0000  11.5000  false\n",
+                " 65535     0.0001   0.0000   0.0001  true\n",
+            ),
+        );
+    }
+}
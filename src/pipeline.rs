@@ -0,0 +1,295 @@
+//! Pipelined CSV parsing.
+//!
+//! For large inputs, parsing records dominates the cost of applying them. This module
+//! splits parsing into stages which run on their own threads, connected by bounded channels
+//! so that a slow consumer applies backpressure all the way back to the file:
+//!
+//! ```text
+//! reader thread -> parser threads -> (reordering) -> engine
+//! ```
+//!
+//! The reader thread only splits the input into chunks of whole lines. Parser threads
+//! tokenize and deserialize those chunks into `Event`s concurrently. The returned iterator
+//! restores the original order, so the engine sees exactly the same sequence of events as it
+//! would by parsing inline.
+//!
+//! Because chunks are split on line boundaries, quoted fields containing newlines are not
+//! supported. No field of an `Event` can legitimately contain one.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Read},
+    sync::{
+        mpsc::{sync_channel, Receiver},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    input::{ParseError, RawRecord},
+    mapping::InputMapping,
+    primitives::Event,
+};
+
+/// PipelineConfig controls the shape of the parsing pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineConfig {
+    /// The number of parser threads.
+    pub parsers: usize,
+    /// The approximate number of bytes handed to a parser thread at once.
+    pub chunk_size: usize,
+    /// The number of chunks which may be in flight in each channel before backpressure applies.
+    pub channel_bound: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            parsers: 2,
+            chunk_size: 256 * 1024,
+            channel_bound: 16,
+        }
+    }
+}
+
+/// A chunk of raw lines, tagged with its position in the sequence of chunks and its first line.
+type RawChunk = (u64, u64, Vec<u8>);
+/// A chunk of parsed records, tagged with its position in the sequence of chunks.
+type ParsedChunk = (u64, Vec<Result<Event, ParseError>>);
+
+/// Parse events from `input`, laid out as described by `mapping`, on background threads.
+///
+/// The returned iterator yields events in input order. Dropping it early shuts the pipeline down.
+pub fn parse_pipelined<R>(
+    input: R,
+    mapping: InputMapping,
+    config: PipelineConfig,
+) -> impl Iterator<Item = Result<Event, ParseError>>
+where
+    R: 'static + Read + Send,
+{
+    let parsers = config.parsers.max(1);
+    let chunk_size = config.chunk_size.max(1);
+    let mut input = BufReader::new(input);
+
+    let mut line = 1;
+    let headers = match mapping.header_record() {
+        Some(headers) => Ok(headers),
+        None => read_headers(&mut input, &mapping, &mut line),
+    };
+    let (header_error, headers) = match headers {
+        Ok(headers) => (None, Some(Arc::new(mapping.compile(&headers)))),
+        Err(err) => (Some(Err(ParseError::from_csv(line, &err))), None),
+    };
+
+    let (raw_sender, raw_receiver) = sync_channel::<RawChunk>(config.channel_bound);
+    let (parsed_sender, parsed_receiver) = sync_channel::<ParsedChunk>(config.channel_bound);
+
+    let error_sender = parsed_sender.clone();
+    std::thread::spawn(move || {
+        let mut seq = 0;
+        loop {
+            let mut chunk = Vec::with_capacity(chunk_size + 128);
+            let first_line = line;
+            let mut error = None;
+            let mut eof = false;
+            while chunk.len() < chunk_size {
+                match input.read_until(b'\n', &mut chunk) {
+                    Ok(0) => {
+                        eof = true;
+                        break;
+                    }
+                    Ok(_) => line += 1,
+                    Err(err) => {
+                        error = Some(err);
+                        break;
+                    }
+                }
+            }
+            if !chunk.is_empty() {
+                if raw_sender.send((seq, first_line, chunk)).is_err() {
+                    break;
+                }
+                seq += 1;
+            }
+            if let Some(err) = error {
+                // read errors take the place of the chunk after the last one read successfully
+                let err = ParseError::from_csv(line, &err.into());
+                let _ = error_sender.send((seq, vec![Err(err)]));
+                break;
+            }
+            if eof {
+                break;
+            }
+        }
+    });
+
+    let raw_receiver = Arc::new(Mutex::new(raw_receiver));
+    let mapping = Arc::new(mapping);
+    if let Some(headers) = headers {
+        for _ in 0..parsers {
+            let raw_receiver = raw_receiver.clone();
+            let parsed_sender = parsed_sender.clone();
+            let headers = headers.clone();
+            let mapping = mapping.clone();
+            std::thread::spawn(move || loop {
+                let next = raw_receiver
+                    .lock()
+                    .expect("parser threads never panic while holding the lock")
+                    .recv();
+                let (seq, first_line, chunk) = match next {
+                    Ok(chunk) => chunk,
+                    Err(_) => break,
+                };
+                let mut reader = mapping
+                    .reader_builder()
+                    .has_headers(false)
+                    .from_reader(chunk.as_slice());
+                let mut record = csv::ByteRecord::new();
+                let mut parsed = Vec::new();
+                loop {
+                    match reader.read_byte_record(&mut record) {
+                        Ok(true) => {
+                            let start = record
+                                .position()
+                                .expect("records read by csv have a position");
+                            let input = &chunk[start.byte() as usize..];
+                            let raw = RawRecord::locate(&reader, &record, input, first_line);
+                            parsed.push(headers.parse(&record, raw))
+                        }
+                        Ok(false) => break,
+                        Err(err) => parsed.push(Err(ParseError::from_csv(first_line, &err))),
+                    }
+                }
+                if parsed_sender.send((seq, parsed)).is_err() {
+                    break;
+                }
+            });
+        }
+    }
+    drop(parsed_sender);
+
+    header_error
+        .into_iter()
+        .chain(Reordered::new(parsed_receiver).flatten())
+}
+
+/// Read lines from `input` until the CSV header has been parsed.
+///
+/// Comments and blank lines preceding the header are consumed along the way; `line` is advanced
+/// past each line consumed.
+fn read_headers<R: BufRead>(
+    input: &mut R,
+    mapping: &InputMapping,
+    line: &mut u64,
+) -> Result<csv::ByteRecord, csv::Error> {
+    let mut text = Vec::new();
+    loop {
+        text.clear();
+        if input.read_until(b'\n', &mut text)? == 0 {
+            return Ok(csv::ByteRecord::new());
+        }
+        *line += 1;
+        let mut reader = mapping
+            .reader_builder()
+            .has_headers(false)
+            .from_reader(text.as_slice());
+        let mut record = csv::ByteRecord::new();
+        if reader.read_byte_record(&mut record)? {
+            return Ok(record);
+        }
+    }
+}
+
+/// Reordered restores input order to chunks which may complete out of order.
+struct Reordered {
+    receiver: Receiver<ParsedChunk>,
+    next: u64,
+    waiting: BTreeMap<u64, Vec<Result<Event, ParseError>>>,
+}
+
+impl Reordered {
+    fn new(receiver: Receiver<ParsedChunk>) -> Self {
+        Reordered {
+            receiver,
+            next: 0,
+            waiting: BTreeMap::new(),
+        }
+    }
+}
+
+impl Iterator for Reordered {
+    type Item = Vec<Result<Event, ParseError>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(chunk) = self.waiting.remove(&self.next) {
+                self.next += 1;
+                return Some(chunk);
+            }
+            let (seq, chunk) = self.receiver.recv().ok()?;
+            self.waiting.insert(seq, chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{input::read_events, primitives::tests::arb_event};
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn pipelined_parsing_matches_inline_parsing(
+            events in proptest::collection::vec(arb_event(10, 1000.0), 0..500),
+            parsers in 1_usize..4,
+            chunk_size in 1_usize..256,
+        ) {
+            let mut data = b"# leading comment\ntype, client, tx, amount\n".to_vec();
+            for (idx, event) in events.iter().enumerate() {
+                if idx % 7 == 3 {
+                    data.extend(b"# interleaved comment\n");
+                }
+                let amount = if event.has_amount() { event.amount.to_string() } else { String::new() };
+                let event_type = format!("{:?}", event.event_type).to_lowercase();
+                data.extend(format!("{event_type}, {}, {}, {amount}\n", event.client, event.tx).bytes());
+            }
+
+            let inline: Vec<_> = read_events(&InputMapping::default(), data.as_slice()).collect();
+
+            let config = PipelineConfig { parsers, chunk_size, channel_bound: 2 };
+            let pipelined: Vec<_> = parse_pipelined(std::io::Cursor::new(data), InputMapping::default(), config).collect();
+
+            prop_assert_eq!(inline.len(), events.len());
+            prop_assert_eq!(pipelined, inline);
+        }
+    }
+
+    #[test]
+    fn pipelined_parsing_reports_bad_records_in_place() {
+        let data = b"# comment\ntype,client,tx,amount\ndeposit,1,1,1\n deposit, 1 ,2,-1 \n# comment\ndeposit,1\ndeposit,1,3,1".to_vec();
+        let inline: Vec<_> = read_events(&InputMapping::default(), data.as_slice()).collect();
+        let errors: Vec<_> = inline
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .collect();
+        let lines: Vec<_> = errors.iter().map(|err| err.line).collect();
+        assert_eq!(lines, [4, 6]);
+        assert_eq!(errors[0].text, " deposit, 1 ,2,-1 ");
+
+        for chunk_size in [1, 40, 1024] {
+            let config = PipelineConfig {
+                chunk_size,
+                ..PipelineConfig::default()
+            };
+            let pipelined: Vec<_> = parse_pipelined(
+                std::io::Cursor::new(data.clone()),
+                InputMapping::default(),
+                config,
+            )
+            .collect();
+            assert_eq!(pipelined, inline);
+        }
+    }
+}
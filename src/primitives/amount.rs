@@ -43,6 +43,85 @@ impl Amount {
     pub const fn is_zero(&self) -> bool {
         self.0 == 0
     }
+
+    /// The underlying representation: the true amount multiplied by 10,000.
+    pub(crate) const fn to_raw(self) -> u64 {
+        self.0
+    }
+
+    /// Construct an amount from its underlying representation.
+    pub(crate) const fn from_raw(raw: u64) -> Self {
+        Amount(raw)
+    }
+}
+
+/// An `AmountDelta` is the signed difference between two amounts.
+///
+/// `Amount` is never negative, so changes in either direction are kept in their own type. Its
+/// range is wide enough that summing the deltas of every client cannot overflow.
+#[derive(
+    Default,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    derive_more::Add,
+    derive_more::AddAssign,
+)]
+pub struct AmountDelta(i128);
+
+impl AmountDelta {
+    pub const ZERO: AmountDelta = AmountDelta(0);
+
+    /// The change from `from` to `to`.
+    pub fn between(from: Amount, to: Amount) -> Self {
+        AmountDelta(i128::from(to.0) - i128::from(from.0))
+    }
+
+    pub const fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    /// The underlying representation: the true delta multiplied by 10,000.
+    pub(crate) const fn to_raw(self) -> i128 {
+        self.0
+    }
+}
+
+/// Deltas are written as amounts are, preceded by their sign unless they are zero.
+impl fmt::Display for AmountDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = match self.0.signum() {
+            1 => "+",
+            -1 => "-",
+            _ => "",
+        };
+        let magnitude = self.0.unsigned_abs();
+        let pre = magnitude / u128::from(AMOUNT_MULTIPLIER);
+        let post = magnitude % u128::from(AMOUNT_MULTIPLIER);
+        if post == 0 {
+            write!(f, "{sign}{pre}")
+        } else {
+            write!(f, "{sign}{pre}.{post:04}")
+        }
+    }
+}
+
+/// Deltas serialize as signed numbers, as amounts do.
+impl Serialize for AmountDelta {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_f64(self.0 as f64 / AMOUNT_MULTIPLIER as f64)
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -94,11 +173,14 @@ impl FromStr for Amount {
                 .map_err(|_| ParseAmountError::OutOfRange)?;
         if let Some(post_str) = captures.name("post") {
             let post_str = post_str.as_str().trim_end_matches('0');
-            let multiplier = 10_u64.pow((4 - post_str.len()) as u32);
-            value += multiplier
-                * post_str
-                    .parse::<u64>()
-                    .expect("any set of 1-4 digits should parse successfully");
+            // a decimal part of only zeros adds nothing
+            if !post_str.is_empty() {
+                let multiplier = 10_u64.pow((4 - post_str.len()) as u32);
+                value += multiplier
+                    * post_str
+                        .parse::<u64>()
+                        .expect("any set of 1-4 digits should parse successfully");
+            }
         }
 
         Ok(Amount(value))
@@ -157,7 +239,27 @@ impl<'de> serde::de::Visitor<'de> for AmountVisitor {
     type Value = Amount;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a positive number with optional decimal")
+        formatter.write_str("a positive number with optional decimal, or a string containing one")
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        value
+            .checked_mul(AMOUNT_MULTIPLIER)
+            .map(Amount)
+            .ok_or_else(|| serde::de::Error::custom(ParseAmountError::OutOfRange))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        match u64::try_from(value) {
+            Ok(value) => self.visit_u64(value),
+            Err(_) => Err(serde::de::Error::custom(AmountFromF64Error::Negative)),
+        }
     }
 
     fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
@@ -171,7 +273,17 @@ impl<'de> serde::de::Visitor<'de> for AmountVisitor {
     where
         E: serde::de::Error,
     {
-        value.try_into().map_err(serde::de::Error::custom)
+        if value.is_finite() && value >= 0.0 {
+            // the shortest decimal which reads back as `value` is the text the producer wrote;
+            // parsing it avoids flooring a product which falls just short, such as 0.57 * 10000
+            value
+                .abs()
+                .to_string()
+                .parse()
+                .map_err(serde::de::Error::custom)
+        } else {
+            value.try_into().map_err(serde::de::Error::custom)
+        }
     }
 }
 
@@ -180,7 +292,9 @@ impl<'de> Deserialize<'de> for Amount {
     where
         D: serde::Deserializer<'de>,
     {
-        deserializer.deserialize_f64(AmountVisitor)
+        // self-describing formats such as JSON may give the amount as a number or a string; CSV
+        // infers a number from the text of the field where it can
+        deserializer.deserialize_any(AmountVisitor)
     }
 }
 
@@ -211,5 +325,41 @@ mod tests {
             let amount: Amount = truncated.parse().expect("this generated string is valid");
             prop_assert_eq!(amount.0, expect);
         }
+
+        #[test]
+        fn parse_amount_accepts_trailing_zeros(pre in 0_u64..=999, zeros in 1_usize..=8) {
+            let string = format!("{pre}.{}", "0".repeat(zeros));
+            let amount: Amount = string.parse().expect("this generated string is valid");
+            prop_assert_eq!(amount.0, pre * AMOUNT_MULTIPLIER);
+        }
+    }
+
+    #[test]
+    fn amounts_deserialize_from_numbers_and_strings() {
+        let parse = |json: &str| serde_json::from_str::<Amount>(json).map(|amount| amount.0);
+        assert_eq!(parse("5").expect("integers are amounts"), 50_000);
+        assert_eq!(parse("1.5").expect("decimals are amounts"), 15_000);
+        assert_eq!(parse("0.57").expect("decimals are exact"), 5_700);
+        assert_eq!(parse("0.00019999").expect("dust is discarded"), 1);
+        assert_eq!(parse(r#""1.2345""#).expect("strings are amounts"), 12_345);
+        assert_eq!(parse(r#""5.0000""#).expect("strings are amounts"), 50_000);
+        assert!(parse("-1").is_err());
+        assert!(parse(r#""-1.0""#).is_err());
+        assert!(parse(r#""one""#).is_err());
+        assert!(parse("true").is_err());
+    }
+
+    #[test]
+    fn deltas_are_signed() {
+        let amount = |raw| Amount(raw);
+        let up = AmountDelta::between(amount(5_000), amount(20_000));
+        let down = AmountDelta::between(amount(20_000), amount(5_000));
+        assert_eq!(up.to_string(), "+1.5000");
+        assert_eq!(down.to_string(), "-1.5000");
+        assert!(down.is_negative());
+        assert!((up + down).is_zero());
+        assert_eq!(AmountDelta::ZERO.to_string(), "0");
+        let widest = AmountDelta::between(amount(0), amount(u64::MAX));
+        assert_eq!((widest + widest).to_raw(), 2 * i128::from(u64::MAX));
     }
 }
@@ -0,0 +1,301 @@
+//! Reconciliation: comparing computed client state against another party's view of it.
+//!
+//! The expected state is read from CSV with the columns `client,available,held,total,locked`, as
+//! client state is written. Every difference between the two is a `Break`.
+
+use std::{collections::BTreeMap, io::Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::amount_text,
+    primitives::{Amount, AmountDelta, ClientId, SerializeClientState},
+};
+
+/// A field of client state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+impl Field {
+    /// Every field, in the order they are written.
+    pub const ALL: [Field; 4] = [Field::Available, Field::Held, Field::Total, Field::Locked];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Field::Available => "available",
+            Field::Held => "held",
+            Field::Total => "total",
+            Field::Locked => "locked",
+        }
+    }
+
+    /// The value of this field in `state`, if it is an amount.
+    pub fn amount(self, state: &SerializeClientState) -> Option<Amount> {
+        match self {
+            Field::Available => Some(state.available),
+            Field::Held => Some(state.held),
+            Field::Total => Some(state.total),
+            Field::Locked => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// A Break is a disagreement between the expected and computed state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Break {
+    /// The client is expected, but has no computed state.
+    Missing(SerializeClientState),
+    /// The client has computed state, but is not expected.
+    Extra(SerializeClientState),
+    /// An amount differs between the expected and computed state.
+    Amount {
+        client: ClientId,
+        field: Field,
+        expected: Amount,
+        actual: Amount,
+    },
+    /// The client's account is locked in one state but not the other.
+    Locked {
+        client: ClientId,
+        expected: bool,
+        actual: bool,
+    },
+}
+
+impl Break {
+    pub fn client(&self) -> ClientId {
+        match self {
+            Break::Missing(state) | Break::Extra(state) => state.client,
+            Break::Amount { client, .. } | Break::Locked { client, .. } => *client,
+        }
+    }
+
+    /// The computed amount less the expected one, for breaks between amounts.
+    pub fn difference(&self) -> Option<AmountDelta> {
+        match self {
+            Break::Amount {
+                expected, actual, ..
+            } => Some(AmountDelta::between(*expected, *actual)),
+            _ => None,
+        }
+    }
+}
+
+/// Reconciliation is the outcome of comparing computed state against the expected state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// Clients whose computed state agreed with the expected state in every field.
+    pub matched: usize,
+    /// Every disagreement, in order of client; a client's breaks are in the order of their fields.
+    pub breaks: Vec<Break>,
+}
+
+impl Reconciliation {
+    /// Whether the computed state agreed with the expected state entirely.
+    pub fn is_clean(&self) -> bool {
+        self.breaks.is_empty()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReconcileError {
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error("client {client} is expected more than once, again on line {line}")]
+    Duplicate { client: ClientId, line: u64 },
+}
+
+/// A row of client state. Amounts are parsed exactly from the text of their fields.
+#[derive(Deserialize)]
+struct ClientRow {
+    client: ClientId,
+    #[serde(deserialize_with = "amount_text")]
+    available: Amount,
+    #[serde(deserialize_with = "amount_text")]
+    held: Amount,
+    #[serde(deserialize_with = "amount_text")]
+    total: Amount,
+    locked: bool,
+}
+
+/// Read the state of each client from CSV with a header row, as client state is written.
+///
+/// The total is kept as given, rather than recomputed, so that it is reconciled in its own right.
+pub fn read_clients<R: Read>(
+    reader: R,
+) -> Result<BTreeMap<ClientId, SerializeClientState>, ReconcileError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let mut record = csv::StringRecord::new();
+    let mut expected = BTreeMap::new();
+    while reader.read_record(&mut record)? {
+        let row: ClientRow = record.deserialize(Some(&headers))?;
+        let state = SerializeClientState {
+            client: row.client,
+            available: row.available,
+            held: row.held,
+            total: row.total,
+            locked: row.locked,
+        };
+        if expected.insert(row.client, state).is_some() {
+            return Err(ReconcileError::Duplicate {
+                client: row.client,
+                line: record.position().map_or(0, csv::Position::line),
+            });
+        }
+    }
+    Ok(expected)
+}
+
+/// Compare the computed state of each client against the expected state.
+pub fn reconcile<I>(
+    mut expected: BTreeMap<ClientId, SerializeClientState>,
+    actual: I,
+) -> Reconciliation
+where
+    I: IntoIterator<Item = SerializeClientState>,
+{
+    let mut reconciliation = Reconciliation::default();
+    for actual in actual {
+        let Some(expected) = expected.remove(&actual.client) else {
+            reconciliation.breaks.push(Break::Extra(actual));
+            continue;
+        };
+        let before = reconciliation.breaks.len();
+        for field in Field::ALL {
+            if let (Some(expected), Some(actual_amount)) =
+                (field.amount(&expected), field.amount(&actual))
+            {
+                if expected != actual_amount {
+                    reconciliation.breaks.push(Break::Amount {
+                        client: actual.client,
+                        field,
+                        expected,
+                        actual: actual_amount,
+                    });
+                }
+            }
+        }
+        if expected.locked != actual.locked {
+            reconciliation.breaks.push(Break::Locked {
+                client: actual.client,
+                expected: expected.locked,
+                actual: actual.locked,
+            });
+        }
+        if reconciliation.breaks.len() == before {
+            reconciliation.matched += 1;
+        }
+    }
+    reconciliation
+        .breaks
+        .extend(expected.into_values().map(Break::Missing));
+    // the sort is stable, so each client's breaks stay in the order of their fields
+    reconciliation.breaks.sort_by_key(Break::client);
+    reconciliation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(amount: &str) -> Amount {
+        amount.parse().expect("test amounts are valid")
+    }
+
+    fn client(client: u16, available: &str, held: &str, locked: bool) -> SerializeClientState {
+        SerializeClientState {
+            client: client.into(),
+            available: amount(available),
+            held: amount(held),
+            total: amount(available) + amount(held),
+            locked,
+        }
+    }
+
+    #[test]
+    fn expected_state_is_read_exactly() {
+        let csv = "client, available, held, total, locked\n\
+                   1, 1234567890123.4567, 0, 1234567890123.4567, false\n\
+                   2, 0.57, 1, 1.57, true\n";
+        let expected = read_clients(csv.as_bytes()).expect("the expected state is valid");
+        assert_eq!(
+            expected.into_values().collect::<Vec<_>>(),
+            [
+                client(1, "1234567890123.4567", "0", false),
+                client(2, "0.57", "1", true),
+            ]
+        );
+
+        let duplicated = "client,available,held,total,locked\n1,0,0,0,false\n1,0,0,0,false\n";
+        assert!(matches!(
+            read_clients(duplicated.as_bytes()),
+            Err(ReconcileError::Duplicate { line: 3, .. })
+        ));
+        let malformed = "client,available,held,total,locked\n1,-1,0,0,false\n";
+        assert!(read_clients(malformed.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn breaks_are_found_in_order_of_client() {
+        let expected: BTreeMap<_, _> = [
+            client(1, "5", "0", false),
+            client(2, "3", "1", false),
+            client(4, "1", "0", false),
+        ]
+        .into_iter()
+        .map(|state| (state.client, state))
+        .collect();
+        let actual = [
+            client(3, "2", "0", false),
+            client(2, "3.5", "0", true),
+            client(1, "5", "0", false),
+        ];
+        let reconciliation = reconcile(expected, actual);
+        assert_eq!(reconciliation.matched, 1);
+        assert!(!reconciliation.is_clean());
+        let breaks = &reconciliation.breaks;
+        assert_eq!(
+            breaks.iter().map(Break::client).collect::<Vec<_>>(),
+            [2, 2, 2, 2, 3, 4].map(ClientId::from)
+        );
+        assert_eq!(
+            breaks[0],
+            Break::Amount {
+                client: 2.into(),
+                field: Field::Available,
+                expected: amount("3"),
+                actual: amount("3.5"),
+            }
+        );
+        assert_eq!(
+            breaks[0].difference().map(|d| d.to_string()),
+            Some("+0.5000".into())
+        );
+        assert_eq!(
+            breaks[1].difference().map(|d| d.to_string()),
+            Some("-1".into())
+        );
+        assert_eq!(
+            breaks[2].difference().map(|d| d.to_string()),
+            Some("-0.5000".into())
+        );
+        assert!(matches!(breaks[3], Break::Locked { actual: true, .. }));
+        assert!(matches!(breaks[4], Break::Extra(_)));
+        assert!(matches!(breaks[5], Break::Missing(_)));
+    }
+}
@@ -0,0 +1,307 @@
+//! Client-sharded event processing.
+//!
+//! Every event affects the balances of exactly one client: the client who owns the deposit or
+//! withdrawal in question. Partitioning events by owning client therefore lets independent
+//! shards process their events in parallel, while preserving the order of each client's events.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::mpsc::SyncSender,
+};
+
+use crate::{
+    feed_records, finish_processing,
+    input::ParseError,
+    primitives::{ClientId, Event, EventType, SerializeClientState, TransactionId},
+    report,
+    state::{
+        snapshot::{
+            expect_tag, invalid_data, read_u16, read_u32, read_u64, write_u16, write_u32,
+            write_u64, write_u8, Snapshot, SHARDED_TAG,
+        },
+        Activity, HistoryEntry, SortKey, StateManager,
+    },
+    EventError, ProcessCounts,
+};
+
+/// Each shard buffers at most this many routed events before backpressure applies.
+const SHARD_CHANNEL_BOUND: usize = 1024;
+
+/// ShardedState partitions global state across several state managers by client.
+///
+/// It is a `StateManager` in its own right, so it can be driven sequentially by `process_events`,
+/// but it is designed to be driven in parallel by `process_events_parallel`.
+///
+/// Deferred references (see `MemoryState::with_pending`) are routed to the shard of the referencing
+/// client, because their owner is not yet known. They are only replayed if that client's shard also
+/// owns the deposit; sharded results are only guaranteed to match sequential results without deferral.
+#[derive(Debug, Clone)]
+pub struct ShardedState<State> {
+    shards: Vec<State>,
+    /// The owning client of each deposit seen so far, so that references to it can be routed.
+    owners: HashMap<TransactionId, ClientId>,
+}
+
+impl<State> ShardedState<State> {
+    /// Create a sharded state with `shards` shards, each constructed by `make_shard`.
+    ///
+    /// At least one shard is always created.
+    pub fn new(shards: usize, make_shard: impl FnMut() -> State) -> Self {
+        ShardedState {
+            shards: std::iter::repeat_with(make_shard)
+                .take(shards.max(1))
+                .collect(),
+            owners: HashMap::new(),
+        }
+    }
+
+    /// The underlying shards.
+    pub fn shards(&self) -> &[State] {
+        &self.shards
+    }
+}
+
+/// Determine which shard must handle `event`, recording the owner of new deposits.
+///
+/// Deposits reusing a known transaction id are routed to the original owner so that the
+/// duplicate is detected exactly as it would be sequentially.
+fn route(owners: &mut HashMap<TransactionId, ClientId>, shards: usize, event: &Event) -> usize {
+    let owner = match event.event_type {
+        EventType::Deposit => *owners.entry(event.tx).or_insert(event.client),
+        EventType::Withdrawal => event.client,
+        EventType::Dispute | EventType::Resolve | EventType::Chargeback => {
+            owners.get(&event.tx).copied().unwrap_or(event.client)
+        }
+    };
+    u16::from(owner) as usize % shards
+}
+
+impl<State> StateManager for ShardedState<State>
+where
+    State: StateManager,
+{
+    type Err = State::Err;
+
+    fn handle_event(&mut self, event: Event) -> Result<(), EventError<Self::Err>> {
+        let shard = route(&mut self.owners, self.shards.len(), &event);
+        self.shards[shard].handle_event(event)
+    }
+
+    fn take_deferred_errors(&mut self) -> Vec<EventError<Self::Err>> {
+        self.shards
+            .iter_mut()
+            .flat_map(StateManager::take_deferred_errors)
+            .collect()
+    }
+
+    fn finish(&mut self) {
+        self.shards.iter_mut().for_each(StateManager::finish);
+    }
+
+    fn activity(&self) -> Activity {
+        self.shards.iter().map(StateManager::activity).sum()
+    }
+
+    fn emit_state(&self) -> Box<dyn '_ + Iterator<Item = SerializeClientState>> {
+        Box::new(self.shards.iter().flat_map(StateManager::emit_state))
+    }
+
+    /// A client's history is kept by the shard which holds the client.
+    fn history(&self, client: ClientId) -> Option<Box<dyn '_ + Iterator<Item = HistoryEntry>>> {
+        self.shards[u16::from(client) as usize % self.shards.len()].history(client)
+    }
+
+    /// Each shard emits its clients in order, and the shards' outputs are merged.
+    fn emit_sorted(&self, key: SortKey) -> Box<dyn '_ + Iterator<Item = SerializeClientState>> {
+        let mut shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.emit_sorted(key).peekable())
+            .collect();
+        Box::new(std::iter::from_fn(move || {
+            let (next, _) = shards
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(idx, shard)| Some((idx, shard.peek()?)))
+                .min_by(|(_, a), (_, b)| key.compare(a, b))?;
+            shards[next].next()
+        }))
+    }
+}
+
+/// The snapshot holds the owner of every deposit, followed by the snapshot of each shard.
+///
+/// It can only be read into sharded state with the same number of shards, because clients are
+/// assigned to shards by the number of shards.
+impl<State> Snapshot for ShardedState<State>
+where
+    State: Snapshot,
+{
+    fn write_snapshot(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_u8(out, SHARDED_TAG)?;
+        write_u64(out, self.shards.len() as u64)?;
+        write_u64(out, self.owners.len() as u64)?;
+        for (tx, client) in &self.owners {
+            write_u32(out, (*tx).into())?;
+            write_u16(out, (*client).into())?;
+        }
+        self.shards
+            .iter()
+            .try_for_each(|shard| shard.write_snapshot(out))
+    }
+
+    fn read_snapshot(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        expect_tag(input, SHARDED_TAG)?;
+        let shards = read_u64(input)?;
+        if shards != self.shards.len() as u64 {
+            return Err(invalid_data(&format!(
+                "snapshot is of {shards} shards, not {}",
+                self.shards.len()
+            )));
+        }
+        self.owners.clear();
+        for _ in 0..read_u64(input)? {
+            let tx = read_u32(input)?.into();
+            self.owners.insert(tx, read_u16(input)?.into());
+        }
+        self.shards
+            .iter_mut()
+            .try_for_each(|shard| shard.read_snapshot(input))
+    }
+}
+
+/// Process a stream of events in parallel, with one worker thread per shard.
+///
+/// See `process_records_parallel`.
+pub fn process_events_parallel<State, I>(
+    state: &mut ShardedState<State>,
+    events: I,
+    errors: Option<SyncSender<EventError<<State as StateManager>::Err>>>,
+) -> ProcessCounts
+where
+    State: StateManager + Send,
+    <State as StateManager>::Err: Send,
+    I: IntoIterator<Item = Event>,
+{
+    process_records_parallel(state, events.into_iter().map(Ok), errors)
+}
+
+/// Process a stream of parsed records in parallel, with one worker thread per shard.
+///
+/// Events are routed to their owning shard on the calling thread, so each client's events are
+/// handled in their original order. Errors from all shards are sent along `errors`, if present;
+/// errors from different shards may interleave in any order. Records which failed to parse are
+/// reported from the calling thread.
+pub fn process_records_parallel<State, I>(
+    state: &mut ShardedState<State>,
+    records: I,
+    errors: Option<SyncSender<EventError<<State as StateManager>::Err>>>,
+) -> ProcessCounts
+where
+    State: StateManager + Send,
+    <State as StateManager>::Err: Send,
+    I: IntoIterator<Item = Result<Event, ParseError>>,
+{
+    let mut counts = feed_records_parallel(state, records, errors.as_ref());
+    if !counts.stopped {
+        counts += finish_processing(state, errors.as_ref());
+    }
+    counts
+}
+
+/// Feed a stream of parsed records into the shards in parallel, without signalling the end of the stream.
+///
+/// See `process_records_parallel` and `feed_records`.
+pub fn feed_records_parallel<State, I>(
+    state: &mut ShardedState<State>,
+    records: I,
+    errors: Option<&SyncSender<EventError<<State as StateManager>::Err>>>,
+) -> ProcessCounts
+where
+    State: StateManager + Send,
+    <State as StateManager>::Err: Send,
+    I: IntoIterator<Item = Result<Event, ParseError>>,
+{
+    let ShardedState { shards, owners } = state;
+    let n_shards = shards.len();
+
+    std::thread::scope(|scope| {
+        let (senders, workers): (Vec<_>, Vec<_>) = shards
+            .iter_mut()
+            .map(|shard| {
+                let (sender, receiver) = std::sync::mpsc::sync_channel(SHARD_CHANNEL_BOUND);
+                let errors = errors.cloned();
+                let worker = scope.spawn(move || {
+                    feed_records(shard, receiver.into_iter().map(Ok), errors.as_ref())
+                });
+                (sender, worker)
+            })
+            .unzip();
+
+        // records are counted here; workers count the events they see and the errors they report
+        let mut counts = ProcessCounts::default();
+        for record in records.into_iter() {
+            counts.records += 1;
+            let event = match record {
+                Ok(event) => event,
+                Err(err) => {
+                    report(errors, [EventError::Parse(err)], &mut counts);
+                    if counts.stopped {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let shard = route(owners, n_shards, &event);
+            if senders[shard].send(event).is_err() {
+                // the worker has stopped early, and has already said why
+                break;
+            }
+        }
+
+        // dropping the senders ends each worker's event stream
+        drop(senders);
+        for worker in workers {
+            let worker_counts = worker.join().expect("shard workers never panic");
+            counts += ProcessCounts {
+                records: 0,
+                ..worker_counts
+            };
+        }
+        counts
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        primitives::tests::{arb_dense_event, valid_events},
+        state::memory::MemoryState,
+    };
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn parallel_processing_matches_sequential(
+            events in proptest::collection::vec(arb_dense_event(50), 200)
+                .prop_map(|events| valid_events(&mut MemoryState::default(), events)),
+            shards in 1_usize..8,
+        ) {
+            let mut sequential = MemoryState::default();
+            let sequential_counts = crate::process_events(&mut sequential, events.clone(), None);
+
+            let mut parallel = ShardedState::new(shards, MemoryState::default);
+            let parallel_counts = process_events_parallel(&mut parallel, events, None);
+
+            prop_assert_eq!(parallel_counts, sequential_counts);
+            prop_assert_eq!(parallel.activity(), sequential.activity());
+
+            for key in [SortKey::Client, SortKey::Available, SortKey::Held, SortKey::Total] {
+                let expect: Vec<_> = sequential.emit_sorted(key).collect();
+                prop_assert_eq!(parallel.emit_sorted(key).collect::<Vec<_>>(), expect);
+            }
+        }
+    }
+}
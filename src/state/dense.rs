@@ -0,0 +1,151 @@
+use crate::{
+    primitives::{ClientId, ClientState},
+    state::memory::ClientTable,
+};
+
+/// There are exactly this many possible client ids.
+const CLIENT_ID_SPACE: usize = u16::MAX as usize + 1;
+const BITMAP_WORDS: usize = CLIENT_ID_SPACE / u64::BITS as usize;
+
+/// DenseClientTable is a client table indexed directly by client id.
+///
+/// Because `ClientId` wraps a `u16`, there are at most 65,536 clients, so the whole id space
+/// fits comfortably in a flat table. An occupancy bitmap distinguishes clients which exist from
+/// slots which merely hold default state.
+///
+/// The table grows to accommodate the largest client id seen so far, and no further.
+#[derive(Debug, Clone)]
+pub struct DenseClientTable {
+    states: Vec<ClientState>,
+    occupied: Vec<u64>,
+}
+
+impl Default for DenseClientTable {
+    fn default() -> Self {
+        DenseClientTable {
+            states: Vec::new(),
+            occupied: vec![0; BITMAP_WORDS],
+        }
+    }
+}
+
+impl DenseClientTable {
+    fn index(client: ClientId) -> usize {
+        u16::from(client) as usize
+    }
+
+    fn is_occupied(&self, idx: usize) -> bool {
+        self.occupied[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    /// The number of clients which exist.
+    pub fn len(&self) -> usize {
+        self.occupied
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.occupied.iter().all(|&word| word == 0)
+    }
+}
+
+impl ClientTable for DenseClientTable {
+    fn get(&self, client: ClientId) -> Option<&ClientState> {
+        let idx = Self::index(client);
+        self.is_occupied(idx).then(|| &self.states[idx])
+    }
+
+    fn get_mut(&mut self, client: ClientId) -> Option<&mut ClientState> {
+        let idx = Self::index(client);
+        if self.is_occupied(idx) {
+            Some(&mut self.states[idx])
+        } else {
+            None
+        }
+    }
+
+    fn get_or_default(&mut self, client: ClientId) -> &mut ClientState {
+        let idx = Self::index(client);
+        if idx >= self.states.len() {
+            self.states.resize_with(idx + 1, ClientState::default);
+        }
+        self.occupied[idx / 64] |= 1 << (idx % 64);
+        &mut self.states[idx]
+    }
+
+    const ORDERED: bool = true;
+
+    /// Clients are always iterated in order of their id.
+    fn iter(&self) -> Box<dyn '_ + Iterator<Item = (ClientId, &ClientState)>> {
+        Box::new(
+            self.occupied
+                .iter()
+                .enumerate()
+                .flat_map(|(word_idx, &word)| {
+                    (0..u64::BITS as usize)
+                        .filter(move |bit| word & (1 << bit) != 0)
+                        .map(move |bit| word_idx * 64 + bit)
+                })
+                .map(|idx| {
+                    let client = ClientId::from(idx as u16);
+                    (client, &self.states[idx])
+                }),
+        )
+    }
+}
+
+impl std::ops::Index<&ClientId> for DenseClientTable {
+    type Output = ClientState;
+
+    fn index(&self, client: &ClientId) -> &ClientState {
+        self.get(*client).expect("indexed clients must exist")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        primitives::tests::arb_event,
+        state::{
+            memory::{DenseMemoryState, MemoryState},
+            SortKey, StateManager,
+        },
+    };
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn dense_layout_matches_hash_layout(
+            events in proptest::collection::vec(arb_event(u16::MAX, 1000.0), 200),
+        ) {
+            let mut hash = MemoryState::default();
+            crate::process_events(&mut hash, events.clone(), None);
+
+            let mut dense = DenseMemoryState::default();
+            crate::process_events(&mut dense, events, None);
+
+            let expect: Vec<_> = hash.emit_sorted(SortKey::Client).collect();
+            prop_assert_eq!(dense.emit_state().collect::<Vec<_>>(), expect.clone());
+            prop_assert_eq!(dense.emit_sorted(SortKey::Client).collect::<Vec<_>>(), expect);
+        }
+    }
+
+    #[test]
+    fn dense_table_tracks_occupancy() {
+        let mut table = DenseClientTable::default();
+        assert!(table.is_empty());
+
+        table.get_or_default(500.into()).locked = true;
+        table.get_or_default(3.into());
+        table.get_or_default(u16::MAX.into());
+
+        assert_eq!(table.len(), 3);
+        assert!(table.get(4.into()).is_none());
+        assert!(table[&500.into()].locked);
+        let ids: Vec<u16> = table.iter().map(|(client, _)| client.into()).collect();
+        assert_eq!(ids, [3, 500, u16::MAX]);
+    }
+}
@@ -0,0 +1,602 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::primitives::{Amount, ClientId, Event, TransactionId};
+
+/// Deposit records keep track of which deposits are under dispute.
+///
+/// Only the fields needed to dispute a deposit are kept: its owner and its amount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepositRecord {
+    pub client: ClientId,
+    pub amount: Amount,
+    pub is_disputed: bool,
+}
+
+impl From<Event> for DepositRecord {
+    fn from(event: Event) -> Self {
+        DepositRecord {
+            client: event.client,
+            amount: event.amount,
+            is_disputed: false,
+        }
+    }
+}
+
+const CLIENT_LEN: usize = 2;
+const AMOUNT_LEN: usize = 8;
+const FLAGS_LEN: usize = 1;
+const PACKED_LEN: usize = CLIENT_LEN + AMOUNT_LEN + FLAGS_LEN;
+const TX_LEN: usize = 4;
+/// On disk, each record is preceded by its transaction id.
+const SPILLED_LEN: usize = TX_LEN + PACKED_LEN;
+const FLAGS_OFFSET: usize = TX_LEN + CLIENT_LEN + AMOUNT_LEN;
+
+const FLAG_DISPUTED: u8 = 0b1;
+
+/// A deposit record packed into 11 bytes, with no alignment padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PackedDeposit([u8; PACKED_LEN]);
+
+impl From<DepositRecord> for PackedDeposit {
+    fn from(record: DepositRecord) -> Self {
+        let mut packed = [0; PACKED_LEN];
+        packed[..CLIENT_LEN].copy_from_slice(&u16::from(record.client).to_le_bytes());
+        packed[CLIENT_LEN..CLIENT_LEN + AMOUNT_LEN]
+            .copy_from_slice(&record.amount.to_raw().to_le_bytes());
+        packed[PACKED_LEN - 1] = if record.is_disputed { FLAG_DISPUTED } else { 0 };
+        PackedDeposit(packed)
+    }
+}
+
+impl From<PackedDeposit> for DepositRecord {
+    fn from(PackedDeposit(packed): PackedDeposit) -> Self {
+        let mut client = [0; CLIENT_LEN];
+        client.copy_from_slice(&packed[..CLIENT_LEN]);
+        let mut amount = [0; AMOUNT_LEN];
+        amount.copy_from_slice(&packed[CLIENT_LEN..CLIENT_LEN + AMOUNT_LEN]);
+        DepositRecord {
+            client: u16::from_le_bytes(client).into(),
+            amount: Amount::from_raw(u64::from_le_bytes(amount)),
+            is_disputed: packed[PACKED_LEN - 1] & FLAG_DISPUTED != 0,
+        }
+    }
+}
+
+/// SpillConfig bounds the memory used by a `DepositStore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpillConfig {
+    /// When the in-memory records are estimated to use this many bytes, they are moved to disk.
+    pub memory_ceiling: usize,
+    /// Spilled records are written to temporary files in this directory.
+    pub directory: PathBuf,
+}
+
+/// A spill run is an immutable-length file of records sorted by transaction id.
+///
+/// Only the flags of a record are ever rewritten in place.
+#[derive(Debug)]
+struct SpillRun {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    min: TransactionId,
+    max: TransactionId,
+}
+
+/// A stream of spilled records, in ascending order of transaction id.
+type SpilledRecords<'a> = Box<dyn 'a + Iterator<Item = io::Result<(TransactionId, PackedDeposit)>>>;
+
+impl SpillRun {
+    /// Write `records`, which must be in ascending order of transaction id, to a new file.
+    ///
+    /// If writing fails, the file is removed.
+    fn write(path: PathBuf, records: SpilledRecords) -> io::Result<Self> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        match Self::write_records(&mut file, records) {
+            Ok((len, min, max)) => Ok(SpillRun {
+                path,
+                file,
+                len,
+                min,
+                max,
+            }),
+            Err(err) => {
+                drop(file);
+                let _ = std::fs::remove_file(&path);
+                Err(err)
+            }
+        }
+    }
+
+    /// Write each record to `file`, returning their number and the least and greatest ids.
+    fn write_records(
+        file: &mut File,
+        records: SpilledRecords,
+    ) -> io::Result<(u64, TransactionId, TransactionId)> {
+        let mut out = io::BufWriter::new(file);
+        let (mut len, mut min, mut max) = (0, None, 0.into());
+        for record in records {
+            let (tx, PackedDeposit(packed)) = record?;
+            out.write_all(&u32::from(tx).to_le_bytes())?;
+            out.write_all(&packed)?;
+            len += 1;
+            min.get_or_insert(tx);
+            max = tx;
+        }
+        out.flush()?;
+        Ok((len, min.unwrap_or(0.into()), max))
+    }
+
+    /// Merge several streams of records into one new file, keeping them in order.
+    ///
+    /// Each transaction id appears in at most one stream, since records are never replaced.
+    fn merge(path: PathBuf, sources: Vec<SpilledRecords>) -> io::Result<Self> {
+        let mut sources: Vec<_> = sources.into_iter().map(Iterator::peekable).collect();
+        let merged = std::iter::from_fn(move || {
+            // an error sorts first, so that it ends the merge at once
+            let (next, _) = sources
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(idx, source)| {
+                    let tx = source.peek()?.as_ref().ok().map(|(tx, _)| *tx);
+                    Some((idx, tx))
+                })
+                .min_by_key(|(_, tx)| *tx)?;
+            sources[next].next()
+        });
+        Self::write(path, Box::new(merged))
+    }
+
+    /// Read every record of this run, in order, independently of lookups.
+    fn records(&self) -> io::Result<SpilledRecords<'static>> {
+        let mut input = io::BufReader::new(File::open(&self.path)?);
+        let mut remaining = self.len;
+        Ok(Box::new(std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let mut buf = [0; SPILLED_LEN];
+            Some(input.read_exact(&mut buf).map(|()| unpack_spilled(&buf)))
+        })))
+    }
+
+    fn read_at(&self, idx: u64) -> io::Result<(TransactionId, PackedDeposit)> {
+        let mut buf = [0; SPILLED_LEN];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(idx * SPILLED_LEN as u64))?;
+        file.read_exact(&mut buf)?;
+        Ok(unpack_spilled(&buf))
+    }
+
+    /// Binary search for `tx`, returning its index and record.
+    fn find(&self, tx: TransactionId) -> io::Result<Option<(u64, PackedDeposit)>> {
+        if self.len == 0 || tx < self.min || tx > self.max {
+            return Ok(None);
+        }
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (found, packed) = self.read_at(mid)?;
+            match found.cmp(&tx) {
+                std::cmp::Ordering::Equal => return Ok(Some((mid, packed))),
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+            }
+        }
+        Ok(None)
+    }
+
+    fn write_flags(&self, idx: u64, flags: u8) -> io::Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(
+            idx * SPILLED_LEN as u64 + FLAGS_OFFSET as u64,
+        ))?;
+        file.write_all(&[flags])
+    }
+}
+
+/// Split a spilled record into its transaction id and packed record.
+fn unpack_spilled(buf: &[u8; SPILLED_LEN]) -> (TransactionId, PackedDeposit) {
+    let mut tx = [0; TX_LEN];
+    tx.copy_from_slice(&buf[..TX_LEN]);
+    let mut packed = [0; PACKED_LEN];
+    packed.copy_from_slice(&buf[TX_LEN..]);
+    (u32::from_le_bytes(tx).into(), PackedDeposit(packed))
+}
+
+impl Drop for SpillRun {
+    fn drop(&mut self) {
+        // spill files are scratch space; failing to clean one up is not worth a panic
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Spill files are numbered uniquely within this process.
+static NEXT_SPILL_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Once this many runs have been spilled, the next spill merges them all into one, so that a
+/// lookup never searches more than this many files.
+const MAX_SPILL_RUNS: usize = 8;
+
+/// A spill moves at least this many records, so that a ceiling below the overhead of the hash
+/// table itself does not write a file, and soon merge them all, for every insert.
+const MIN_SPILL_RECORDS: usize = 64;
+
+/// The estimated bytes used by each record in memory: the hash table stores each entry unboxed,
+/// alongside one control byte.
+const RECORD_MEMORY: usize = std::mem::size_of::<(TransactionId, PackedDeposit)>() + 1;
+
+/// DepositStats describes the storage used by a `DepositStore`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DepositStats {
+    /// Records held in memory.
+    pub in_memory: usize,
+    /// Estimated bytes used by records held in memory, including hash table overhead.
+    pub memory_bytes: usize,
+    /// Records spilled to disk.
+    pub spilled: u64,
+    /// Bytes used by records spilled to disk.
+    pub spilled_bytes: u64,
+    /// Files holding spilled records.
+    pub spill_files: usize,
+}
+
+impl std::ops::Add for DepositStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        DepositStats {
+            in_memory: self.in_memory + other.in_memory,
+            memory_bytes: self.memory_bytes + other.memory_bytes,
+            spilled: self.spilled + other.spilled,
+            spilled_bytes: self.spilled_bytes + other.spilled_bytes,
+            spill_files: self.spill_files + other.spill_files,
+        }
+    }
+}
+
+impl std::iter::Sum for DepositStats {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(DepositStats::default(), std::ops::Add::add)
+    }
+}
+
+impl std::fmt::Display for DepositStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "deposits: {} in memory (~{} bytes), {} spilled to disk ({} bytes in {} files)",
+            self.in_memory, self.memory_bytes, self.spilled, self.spilled_bytes, self.spill_files,
+        )
+    }
+}
+
+/// DepositStore keeps a compact record of every deposit, by transaction id.
+///
+/// Records are packed into 11 bytes plus their key. When a `SpillConfig` is present and the
+/// in-memory records reach its ceiling, they are sorted and written to a file, where they
+/// remain searchable and their dispute status remains mutable. At most `MAX_SPILL_RUNS` files
+/// are kept; further spills merge them.
+#[derive(Debug, Default)]
+pub struct DepositStore {
+    memory: HashMap<TransactionId, PackedDeposit>,
+    spill: Option<SpillConfig>,
+    runs: Vec<SpillRun>,
+}
+
+impl DepositStore {
+    pub fn new(spill: Option<SpillConfig>) -> Self {
+        DepositStore {
+            spill,
+            ..DepositStore::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len() + self.runs.iter().map(|run| run.len as usize).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimate the bytes used by the in-memory records, including the table's spare capacity.
+    pub fn memory_usage(&self) -> usize {
+        self.memory.capacity() * RECORD_MEMORY
+    }
+
+    pub fn stats(&self) -> DepositStats {
+        let spilled = self.runs.iter().map(|run| run.len).sum();
+        DepositStats {
+            in_memory: self.memory.len(),
+            memory_bytes: self.memory_usage(),
+            spilled,
+            spilled_bytes: spilled * SPILLED_LEN as u64,
+            spill_files: self.runs.len(),
+        }
+    }
+
+    pub fn get(&self, tx: TransactionId) -> io::Result<Option<DepositRecord>> {
+        if let Some(packed) = self.memory.get(&tx) {
+            return Ok(Some((*packed).into()));
+        }
+        for run in self.runs.iter().rev() {
+            if let Some((_, packed)) = run.find(tx)? {
+                return Ok(Some(packed.into()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get an existing record, panicking if it is absent or cannot be read.
+    #[cfg(test)]
+    pub(crate) fn record(&self, tx: TransactionId) -> DepositRecord {
+        self.get(tx)
+            .expect("test deposit lookups succeed")
+            .expect("test deposit exists")
+    }
+
+    pub fn contains(&self, tx: TransactionId) -> io::Result<bool> {
+        self.get(tx).map(|record| record.is_some())
+    }
+
+    /// Insert a new record.
+    ///
+    /// Callers must ensure that `tx` is not already present; records are never replaced.
+    pub fn insert(&mut self, tx: TransactionId, record: DepositRecord) -> io::Result<()> {
+        self.insert_packed(tx, record.into())
+    }
+
+    fn insert_packed(&mut self, tx: TransactionId, packed: PackedDeposit) -> io::Result<()> {
+        self.memory.insert(tx, packed);
+        match &self.spill {
+            // the spare capacity is left out, since it is not freed until the records are spilled
+            Some(spill)
+                if self.memory.len() >= MIN_SPILL_RECORDS
+                    && self.memory.len() * RECORD_MEMORY >= spill.memory_ceiling =>
+            {
+                let spilled = self.spill_to_disk();
+                if spilled.is_err() {
+                    // the caller treats the insert as failed, so the record must not remain
+                    self.memory.remove(&tx);
+                }
+                spilled
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Update the dispute status of an existing record.
+    pub fn set_disputed(&mut self, tx: TransactionId, is_disputed: bool) -> io::Result<()> {
+        let flags = if is_disputed { FLAG_DISPUTED } else { 0 };
+        if let Some(PackedDeposit(packed)) = self.memory.get_mut(&tx) {
+            packed[PACKED_LEN - 1] = flags;
+            return Ok(());
+        }
+        for run in self.runs.iter().rev() {
+            if let Some((idx, _)) = run.find(tx)? {
+                return run.write_flags(idx, flags);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write every record to `out`, preceded by their number, in the layout of a spill file.
+    pub(crate) fn write_records(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&(self.len() as u64).to_le_bytes())?;
+        for (tx, PackedDeposit(packed)) in &self.memory {
+            out.write_all(&u32::from(*tx).to_le_bytes())?;
+            out.write_all(packed)?;
+        }
+        for run in &self.runs {
+            let mut file = &run.file;
+            file.seek(SeekFrom::Start(0))?;
+            let len = run.len * SPILLED_LEN as u64;
+            if io::copy(&mut file.take(len), out)? != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace every record with those written by `write_records`, spilling as they are inserted.
+    pub(crate) fn read_records(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.memory.clear();
+        self.runs.clear();
+        let mut count = [0; 8];
+        input.read_exact(&mut count)?;
+        let mut buf = [0; SPILLED_LEN];
+        for _ in 0..u64::from_le_bytes(count) {
+            input.read_exact(&mut buf)?;
+            let (tx, packed) = unpack_spilled(&buf);
+            self.insert_packed(tx, packed)?;
+        }
+        Ok(())
+    }
+
+    /// Skip over the records written by `write_records`, without storing them.
+    pub(crate) fn skip_records(input: &mut dyn Read) -> io::Result<()> {
+        let mut count = [0; 8];
+        input.read_exact(&mut count)?;
+        let len = u64::from_le_bytes(count)
+            .checked_mul(SPILLED_LEN as u64)
+            .ok_or(io::ErrorKind::InvalidData)?;
+        if io::copy(&mut (&mut *input).take(len), &mut io::sink())? != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    /// Move the in-memory records to a new run, or merge them with every run into one.
+    ///
+    /// Nothing is removed until the new file has been written, so a failure loses no records.
+    fn spill_to_disk(&mut self) -> io::Result<()> {
+        let directory = match &self.spill {
+            Some(spill) => &spill.directory,
+            None => return Ok(()),
+        };
+        let path = directory.join(format!(
+            "transacty-deposits-{}-{}.bin",
+            std::process::id(),
+            NEXT_SPILL_FILE.fetch_add(1, Ordering::Relaxed),
+        ));
+        let mut records: Vec<_> = self
+            .memory
+            .iter()
+            .map(|(tx, packed)| (*tx, *packed))
+            .collect();
+        records.sort_unstable_by_key(|(tx, _)| *tx);
+        let records = Box::new(records.into_iter().map(Ok));
+        let merge = self.runs.len() + 1 >= MAX_SPILL_RUNS;
+        let run = if merge {
+            let mut sources = self
+                .runs
+                .iter()
+                .map(SpillRun::records)
+                .collect::<io::Result<Vec<_>>>()?;
+            sources.push(records);
+            SpillRun::merge(path, sources)?
+        } else {
+            SpillRun::write(path, records)?
+        };
+        if merge {
+            // dropping the merged runs removes their files
+            self.runs.clear();
+        }
+        self.runs.push(run);
+        self.memory.clear();
+        self.memory.shrink_to_fit();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    prop_compose! {
+        fn arb_record()(client: u16, raw_amount: u64, is_disputed: bool) -> DepositRecord {
+            DepositRecord { client: client.into(), amount: Amount::from_raw(raw_amount), is_disputed }
+        }
+    }
+
+    #[test]
+    fn packed_records_have_no_padding() {
+        assert_eq!(std::mem::size_of::<PackedDeposit>(), PACKED_LEN);
+        assert_eq!(std::mem::align_of::<PackedDeposit>(), 1);
+        assert!(std::mem::size_of::<(TransactionId, PackedDeposit)>() <= 16);
+    }
+
+    #[test]
+    fn failed_spills_keep_records_in_memory() {
+        let directory = std::env::temp_dir().join("transacty-no-such-directory");
+        let mut store = DepositStore::new(Some(SpillConfig {
+            memory_ceiling: 200,
+            directory,
+        }));
+        let record = DepositRecord {
+            client: 1.into(),
+            amount: Amount::from_raw(1),
+            is_disputed: false,
+        };
+        let mut failed = None;
+        for tx in 0..100 {
+            if store.insert(tx.into(), record).is_err() {
+                failed = Some(tx);
+                break;
+            }
+        }
+        let failed = failed.expect("the spill directory does not exist");
+        assert_eq!(store.len(), failed as usize);
+        assert_eq!(store.get(failed.into()).expect("nothing was spilled"), None);
+        for tx in 0..failed {
+            assert_eq!(
+                store.get(tx.into()).expect("nothing was spilled"),
+                Some(record)
+            );
+        }
+    }
+
+    #[test]
+    fn tiny_ceilings_spill_in_batches() {
+        let mut store = DepositStore::new(Some(SpillConfig {
+            memory_ceiling: 1,
+            directory: std::env::temp_dir(),
+        }));
+        let record = DepositRecord {
+            client: 1.into(),
+            amount: Amount::from_raw(1),
+            is_disputed: false,
+        };
+        let mut txs = 0_u32..;
+        let mut insert = |store: &mut DepositStore, count: usize| {
+            for tx in (&mut txs).take(count) {
+                store
+                    .insert(tx.into(), record)
+                    .expect("spilling to the temp dir succeeds");
+            }
+        };
+
+        insert(&mut store, MIN_SPILL_RECORDS - 1);
+        assert_eq!(store.stats().spill_files, 0);
+        insert(&mut store, 1 + (MAX_SPILL_RUNS - 2) * MIN_SPILL_RECORDS);
+        assert_eq!(store.stats().spill_files, MAX_SPILL_RUNS - 1);
+        assert_eq!(store.stats().in_memory, 0);
+        // the next batch merges every run into one
+        insert(&mut store, MIN_SPILL_RECORDS);
+        assert_eq!(store.stats().spill_files, 1);
+        assert_eq!(store.len(), MAX_SPILL_RUNS * MIN_SPILL_RECORDS);
+        assert_eq!(
+            store.get(0.into()).expect("spill files are readable"),
+            Some(record)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn packing_round_trips(record in arb_record()) {
+            prop_assert_eq!(DepositRecord::from(PackedDeposit::from(record)), record);
+        }
+
+        #[test]
+        fn spilled_records_remain_accessible(
+            records in proptest::collection::hash_map(any::<u32>(), arb_record(), 100..600),
+            disputes in proptest::collection::vec(any::<prop::sample::Index>(), 0..50),
+        ) {
+            let directory = std::env::temp_dir();
+            let mut store = DepositStore::new(Some(SpillConfig { memory_ceiling: 200, directory }));
+            let mut expect = HashMap::new();
+            for (tx, record) in records {
+                store.insert(tx.into(), record).expect("spilling to the temp dir succeeds");
+                expect.insert(TransactionId::from(tx), record);
+            }
+            prop_assert!(store.stats().spill_files > 0);
+            prop_assert!(store.stats().spill_files <= MAX_SPILL_RUNS);
+            prop_assert_eq!(store.len(), expect.len());
+
+            let txs: Vec<_> = expect.keys().copied().collect();
+            for idx in disputes {
+                let tx = *idx.get(&txs);
+                let record = expect.get_mut(&tx).expect("tx was chosen from expected records");
+                record.is_disputed = !record.is_disputed;
+                store.set_disputed(tx, record.is_disputed).expect("spill files are writable");
+            }
+
+            for (tx, record) in expect {
+                prop_assert_eq!(store.get(tx).expect("spill files are readable"), Some(record));
+            }
+        }
+    }
+}
@@ -1,57 +1,530 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Write},
+};
 
 use crate::{
-    primitives::{ClientId, ClientState, Event, EventType, TransactionId},
-    state::StateManager,
+    primitives::{ClientId, ClientState, Event, EventType, SerializeClientState, TransactionId},
+    state::{
+        dense::DenseClientTable,
+        deposits::{DepositStats, DepositStore, SpillConfig},
+        snapshot::{
+            expect_tag, invalid_data, read_amount, read_event, read_u16, read_u64, read_u8,
+            write_amount, write_event, write_u16, write_u64, write_u8, Snapshot, MEMORY_TAG,
+        },
+        sort_clients, Activity, HistoryEntry, SortKey, StateManager,
+    },
     EventError,
 };
 
-/// Deposit records keep track of which deposits are under dispute
-#[derive(Debug, Clone)]
-pub(crate) struct DepositRecord {
-    pub(crate) event: Event,
-    pub(crate) is_disputed: bool,
+/// PendingConfig bounds the queue of events which reference transactions not yet seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingConfig {
+    /// At most this many events may be parked at once; beyond that, the oldest expire.
+    pub max_count: usize,
+    /// A parked event expires once this many subsequent events have been handled.
+    pub max_age: u64,
+}
+
+impl Default for PendingConfig {
+    fn default() -> Self {
+        PendingConfig {
+            max_count: 10_000,
+            max_age: 100_000,
+        }
+    }
+}
+
+/// The pending queue parks dispute, resolve, and chargeback events whose deposit has not yet arrived.
+///
+/// Parked events are indexed both by arrival sequence, for expiry, and by transaction, for replay.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PendingQueue {
+    config: PendingConfig,
+    by_seq: BTreeMap<u64, Event>,
+    by_tx: HashMap<TransactionId, Vec<u64>>,
 }
 
-impl From<Event> for DepositRecord {
-    fn from(event: Event) -> Self {
-        DepositRecord {
-            event,
-            is_disputed: false,
+impl PendingQueue {
+    fn new(config: PendingConfig) -> Self {
+        PendingQueue {
+            config,
+            ..PendingQueue::default()
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.by_seq.len()
+    }
+
+    fn park(&mut self, seq: u64, event: Event) {
+        self.by_tx.entry(event.tx).or_default().push(seq);
+        self.by_seq.insert(seq, event);
+    }
+
+    /// Remove and return all events waiting on `tx`, in arrival order.
+    fn take(&mut self, tx: TransactionId) -> Vec<Event> {
+        self.by_tx
+            .remove(&tx)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|seq| self.by_seq.remove(&seq))
+            .collect()
+    }
+
+    fn pop_oldest(&mut self) -> Option<Event> {
+        let (&seq, _) = self.by_seq.iter().next()?;
+        let event = self.by_seq.remove(&seq)?;
+        if let Some(seqs) = self.by_tx.get_mut(&event.tx) {
+            seqs.retain(|&s| s != seq);
+            if seqs.is_empty() {
+                self.by_tx.remove(&event.tx);
+            }
+        }
+        Some(event)
+    }
+
+    /// Remove and return all events which are too old or which overflow the queue, as of `now`.
+    fn expire(&mut self, now: u64) -> Vec<Event> {
+        let mut expired = Vec::new();
+        while let Some((&seq, _)) = self.by_seq.iter().next() {
+            let too_old = now.saturating_sub(seq) > self.config.max_age;
+            if !too_old && self.len() <= self.config.max_count {
+                break;
+            }
+            expired.extend(self.pop_oldest());
         }
+        expired
+    }
+
+    fn expire_all(&mut self) -> Vec<Event> {
+        std::iter::from_fn(|| self.pop_oldest()).collect()
+    }
+}
+
+/// A ClientTable stores the state of each client, for use by `GenericMemoryState`.
+pub trait ClientTable {
+    /// Get the state of `client`, if it exists.
+    fn get(&self, client: ClientId) -> Option<&ClientState>;
+
+    /// Get the state of `client` for modification, if it exists.
+    fn get_mut(&mut self, client: ClientId) -> Option<&mut ClientState>;
+
+    /// Get the state of `client` for modification, creating it if it does not exist.
+    fn get_or_default(&mut self, client: ClientId) -> &mut ClientState;
+
+    /// Iterate over all clients which exist, in no particular order unless `ORDERED`.
+    fn iter(&self) -> Box<dyn '_ + Iterator<Item = (ClientId, &ClientState)>>;
+
+    /// Whether `iter` yields clients in ascending order of their id.
+    const ORDERED: bool = false;
+}
+
+impl ClientTable for HashMap<ClientId, ClientState> {
+    fn get(&self, client: ClientId) -> Option<&ClientState> {
+        HashMap::get(self, &client)
+    }
+
+    fn get_mut(&mut self, client: ClientId) -> Option<&mut ClientState> {
+        HashMap::get_mut(self, &client)
+    }
+
+    fn get_or_default(&mut self, client: ClientId) -> &mut ClientState {
+        self.entry(client).or_default()
+    }
+
+    fn iter(&self) -> Box<dyn '_ + Iterator<Item = (ClientId, &ClientState)>> {
+        Box::new(HashMap::iter(self).map(|(client, state)| (*client, state)))
     }
 }
 
-/// MemoryState is a state manager which keeps everything resident in local memory.
+/// GenericMemoryState is a state manager which keeps everything resident in local memory.
 ///
 /// It's simple and fast, but unsuitable for production; production data stores
 /// would like to have something with persistence, and something which can better
 /// handle large states.
-#[derive(Default, Debug, Clone)]
-pub struct MemoryState {
-    pub(crate) client_state: HashMap<ClientId, ClientState>,
-    pub(crate) deposits: HashMap<TransactionId, DepositRecord>,
+///
+/// The layout of the client table is chosen by the `Clients` parameter; see `MemoryState`
+/// and `DenseMemoryState`.
+///
+/// It is not `Clone`: spilled deposits live in files which the state owns and removes when it is
+/// dropped, and deferred errors may hold an `io::Error`. To copy a state, write a snapshot of it.
+#[derive(Default, Debug)]
+pub struct GenericMemoryState<Clients> {
+    pub(crate) client_state: Clients,
+    pub(crate) deposits: DepositStore,
+    /// When present, references to unknown transactions are parked here instead of being dropped.
+    pub(crate) pending: Option<PendingQueue>,
+    /// Count of events handled so far; used to age pending events.
+    pub(crate) seq: u64,
+    pub(crate) deferred_errors: Vec<EventError<io::Error>>,
+    pub(crate) activity: Activity,
+    /// When present, the events applied to each client are recorded here.
+    pub(crate) history: Option<HashMap<ClientId, Vec<HistoryEntry>>>,
+}
+
+/// MemoryState keeps its clients in a `HashMap`, and emits them in arbitrary order.
+pub type MemoryState = GenericMemoryState<HashMap<ClientId, ClientState>>;
+
+/// DenseMemoryState keeps its clients in a table indexed directly by client id.
+///
+/// This avoids hashing on the hot path, and emits clients in order of their id.
+pub type DenseMemoryState = GenericMemoryState<DenseClientTable>;
+
+impl<Clients> GenericMemoryState<Clients>
+where
+    Clients: ClientTable + Default,
+{
+    /// Construct a state manager which defers events referencing not-yet-seen transactions.
+    ///
+    /// Deferred events are replayed when the matching deposit arrives, and reported as
+    /// `EventError::PendingReferenceExpired` if it does not arrive within the configured bounds.
+    pub fn with_pending(config: PendingConfig) -> Self {
+        Self::new(Some(config), None)
+    }
+
+    /// Construct a state manager, optionally deferring events referencing not-yet-seen transactions,
+    /// and optionally spilling deposit records to disk beyond a memory ceiling.
+    pub fn new(pending: Option<PendingConfig>, spill: Option<SpillConfig>) -> Self {
+        GenericMemoryState {
+            deposits: DepositStore::new(spill),
+            pending: pending.map(PendingQueue::new),
+            ..GenericMemoryState::default()
+        }
+    }
+
+    /// Keep a history of the events applied to each client, to be queried with
+    /// `StateManager::history`.
+    ///
+    /// The history grows with every applied event, so it is best kept for short runs.
+    pub fn with_history(mut self) -> Self {
+        self.history = Some(HashMap::new());
+        self
+    }
+
+    /// Record `event` in the history of `client`, along with the client's state now that it has
+    /// been applied.
+    fn record(&mut self, client: ClientId, event: &Event) {
+        if let Some(history) = &mut self.history {
+            let state = self
+                .client_state
+                .get(client)
+                .cloned()
+                .unwrap_or_default()
+                .to_serialize(client);
+            history.entry(client).or_default().push(HistoryEntry {
+                seq: self.seq,
+                event: event.clone(),
+                state,
+            });
+        }
+    }
+
+    /// Describe the storage used by deposit records.
+    pub fn deposit_stats(&self) -> DepositStats {
+        self.deposits.stats()
+    }
+
+    /// Park `event` if deferral is enabled; otherwise it is dropped.
+    fn park(&mut self, event: Event) {
+        match &mut self.pending {
+            Some(pending) => pending.park(self.seq, event),
+            None => self.activity.ignored += 1,
+        }
+    }
+
+    fn expire_pending(&mut self, all: bool) {
+        if let Some(pending) = &mut self.pending {
+            let expired = if all {
+                pending.expire_all()
+            } else {
+                pending.expire(self.seq)
+            };
+            self.deferred_errors.extend(
+                expired
+                    .into_iter()
+                    .map(|event| EventError::PendingReferenceExpired(event.client, event.tx)),
+            );
+        }
+    }
+
+    /// Replay all events which were waiting on `tx`.
+    fn replay_pending(&mut self, tx: TransactionId) {
+        let waiting = match &mut self.pending {
+            Some(pending) => pending.take(tx),
+            None => return,
+        };
+        for event in waiting {
+            if let Err(err) = self.apply(event) {
+                self.deferred_errors.push(err);
+            }
+        }
+    }
 }
 
-impl StateManager for MemoryState {
-    type Err = ();
+impl<Clients> StateManager for GenericMemoryState<Clients>
+where
+    Clients: ClientTable + Default,
+{
+    type Err = io::Error;
 
     fn handle_event(&mut self, event: Event) -> Result<(), EventError<Self::Err>> {
+        self.seq += 1;
+        let result = self.apply(event);
+        self.expire_pending(false);
+        result
+    }
+
+    fn take_deferred_errors(&mut self) -> Vec<EventError<Self::Err>> {
+        std::mem::take(&mut self.deferred_errors)
+    }
+
+    fn finish(&mut self) {
+        self.expire_pending(true);
+    }
+
+    fn emit_state(&self) -> Box<dyn '_ + Iterator<Item = SerializeClientState>> {
+        Box::new(
+            self.client_state
+                .iter()
+                .map(|(client_id, client_state)| client_state.to_serialize(client_id)),
+        )
+    }
+
+    fn activity(&self) -> Activity {
+        self.activity
+    }
+
+    fn emit_sorted(&self, key: SortKey) -> Box<dyn '_ + Iterator<Item = SerializeClientState>> {
+        if key == SortKey::Client && Clients::ORDERED {
+            self.emit_state()
+        } else {
+            sort_clients(self.emit_state(), key)
+        }
+    }
+
+    fn history(&self, client: ClientId) -> Option<Box<dyn '_ + Iterator<Item = HistoryEntry>>> {
+        let entries = self.history.as_ref()?.get(&client);
+        Some(Box::new(entries.into_iter().flatten().cloned()))
+    }
+}
+
+/// The snapshot holds, in order: the event sequence number, the activity tallies, every client,
+/// every deposit record, every deferred reference with its sequence number, and the history of
+/// each client.
+impl<Clients> Snapshot for GenericMemoryState<Clients>
+where
+    Clients: ClientTable + Default,
+{
+    fn write_snapshot(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_u8(out, MEMORY_TAG)?;
+        write_u64(out, self.seq)?;
+
+        let activity = &self.activity;
+        write_u64(out, activity.applied as u64)?;
+        write_u64(out, activity.ignored as u64)?;
+        for amount in [
+            activity.deposited,
+            activity.withdrawn,
+            activity.disputed,
+            activity.resolved,
+            activity.charged_back,
+        ] {
+            write_amount(out, amount)?;
+        }
+        write_u64(out, activity.newly_locked as u64)?;
+
+        write_u64(out, self.client_state.iter().count() as u64)?;
+        for (client, state) in self.client_state.iter() {
+            write_u16(out, client.into())?;
+            write_amount(out, state.available)?;
+            write_amount(out, state.held)?;
+            write_u8(out, state.locked.into())?;
+        }
+
+        self.deposits.write_records(out)?;
+
+        let pending = self.pending.iter().flat_map(|pending| &pending.by_seq);
+        write_u64(
+            out,
+            self.pending.as_ref().map_or(0, PendingQueue::len) as u64,
+        )?;
+        for (seq, event) in pending {
+            write_u64(out, *seq)?;
+            write_event(out, event)?;
+        }
+
+        let histories = self.history.iter().flatten();
+        write_u64(out, self.history.as_ref().map_or(0, HashMap::len) as u64)?;
+        for (client, entries) in histories {
+            write_u16(out, (*client).into())?;
+            write_u64(out, entries.len() as u64)?;
+            for entry in entries {
+                write_u64(out, entry.seq)?;
+                write_event(out, &entry.event)?;
+                write_amount(out, entry.state.available)?;
+                write_amount(out, entry.state.held)?;
+                write_u8(out, entry.state.locked.into())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_snapshot(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        expect_tag(input, MEMORY_TAG)?;
+        self.seq = read_u64(input)?;
+
+        self.activity = Activity {
+            applied: read_u64(input)? as usize,
+            ignored: read_u64(input)? as usize,
+            deposited: read_amount(input)?,
+            withdrawn: read_amount(input)?,
+            disputed: read_amount(input)?,
+            resolved: read_amount(input)?,
+            charged_back: read_amount(input)?,
+            newly_locked: read_u64(input)? as usize,
+        };
+
+        self.client_state = Clients::default();
+        for _ in 0..read_u64(input)? {
+            let client = read_u16(input)?.into();
+            *self.client_state.get_or_default(client) = read_client_state(input)?;
+        }
+
+        self.deposits.read_records(input)?;
+
+        let parked = read_u64(input)?;
+        match &mut self.pending {
+            Some(pending) => {
+                *pending = PendingQueue::new(pending.config);
+                for _ in 0..parked {
+                    let seq = read_u64(input)?;
+                    pending.park(seq, read_event(input)?);
+                }
+            }
+            None if parked > 0 => {
+                return Err(invalid_data(
+                    "snapshot holds deferred references, but deferral is not enabled",
+                ))
+            }
+            None => {}
+        }
+
+        let histories = read_u64(input)?;
+        match &mut self.history {
+            Some(history) => {
+                history.clear();
+                for _ in 0..histories {
+                    let client = read_u16(input)?.into();
+                    history.insert(client, read_history(input, client)?);
+                }
+            }
+            None if histories > 0 => {
+                return Err(invalid_data(
+                    "snapshot holds client history, but history is not enabled",
+                ))
+            }
+            None => {}
+        }
+
+        self.deferred_errors.clear();
+        Ok(())
+    }
+}
+
+impl<Clients> GenericMemoryState<Clients>
+where
+    Clients: ClientTable + Default,
+{
+    /// Add the clients, and any history kept of them, from a snapshot written by `write_snapshot`.
+    ///
+    /// Deposit records and deferred references are skipped over rather than read, so the result
+    /// answers queries about its clients but cannot carry on processing. Nothing is replaced, so
+    /// the shards of a sharded snapshot can be read one after another into the same state; each
+    /// client belongs to only one shard.
+    pub fn read_client_snapshot(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        expect_tag(input, MEMORY_TAG)?;
+        // the sequence number, then the activity tallies
+        for _ in 0..9 {
+            read_u64(input)?;
+        }
+
+        for _ in 0..read_u64(input)? {
+            let client = read_u16(input)?.into();
+            *self.client_state.get_or_default(client) = read_client_state(input)?;
+        }
+
+        DepositStore::skip_records(input)?;
+        for _ in 0..read_u64(input)? {
+            read_u64(input)?;
+            read_event(input)?;
+        }
+
+        for _ in 0..read_u64(input)? {
+            let client = read_u16(input)?.into();
+            let entries = read_history(input, client)?;
+            if let Some(history) = &mut self.history {
+                history.insert(client, entries);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_client_state(input: &mut dyn Read) -> io::Result<ClientState> {
+    Ok(ClientState {
+        available: read_amount(input)?,
+        held: read_amount(input)?,
+        locked: read_u8(input)? != 0,
+    })
+}
+
+fn read_history(input: &mut dyn Read, client: ClientId) -> io::Result<Vec<HistoryEntry>> {
+    (0..read_u64(input)?)
+        .map(|_| {
+            Ok(HistoryEntry {
+                seq: read_u64(input)?,
+                event: read_event(input)?,
+                state: read_client_state(input)?.to_serialize(client),
+            })
+        })
+        .collect()
+}
+
+impl<Clients> GenericMemoryState<Clients>
+where
+    Clients: ClientTable + Default,
+{
+    /// Apply a single event to the state, without any bookkeeping for the pending queue.
+    fn apply(&mut self, event: Event) -> Result<(), EventError<io::Error>> {
         match event.event_type {
             EventType::Deposit => {
-                if self.deposits.contains_key(&event.tx) {
-                    return Err(EventError::DuplicateTransactionId(event.tx));
+                if self
+                    .deposits
+                    .contains(event.tx)
+                    .map_err(EventError::StateError)?
+                {
+                    return Err(EventError::DuplicateTransactionId(event.client, event.tx));
                 }
 
-                self.client_state.entry(event.client).or_default().available += event.amount;
-                self.deposits.insert(event.tx, event.into());
+                let Event {
+                    client, tx, amount, ..
+                } = event;
+                self.deposits
+                    .insert(tx, event.clone().into())
+                    .map_err(EventError::StateError)?;
+                self.client_state.get_or_default(client).available += amount;
+                self.activity.applied += 1;
+                self.activity.deposited += amount;
+                self.record(client, &event);
+                self.replay_pending(tx);
             }
 
             EventType::Withdrawal => {
                 let state = self
                     .client_state
-                    .get_mut(&event.client)
-                    .ok_or(EventError::UnknownClient(event.client))?;
+                    .get_mut(event.client)
+                    .ok_or(EventError::UnknownClient(event.client, event.tx))?;
 
                 if state.available < event.amount {
                     return Err(EventError::InsufficientFunds(event.client, event.tx));
@@ -61,72 +534,240 @@ impl StateManager for MemoryState {
                 }
 
                 state.available -= event.amount;
+                self.activity.applied += 1;
+                self.activity.withdrawn += event.amount;
+                self.record(event.client, &event);
             }
 
             EventType::Dispute => {
-                if let Some(record) = self.deposits.get_mut(&event.tx) {
+                let record = self
+                    .deposits
+                    .get(event.tx)
+                    .map_err(EventError::StateError)?;
+                if let Some(record) = record {
                     if record.is_disputed {
                         return Err(EventError::DoubleDispute(event.client, event.tx));
                     }
 
                     let state = self
                         .client_state
-                        .get_mut(&record.event.client)
-                        .ok_or(EventError::UnknownClient(event.client))?;
+                        .get_mut(record.client)
+                        .ok_or(EventError::UnknownClient(event.client, event.tx))?;
 
-                    record.is_disputed = true;
-                    state.available -= record.event.amount;
-                    state.held += record.event.amount;
+                    self.deposits
+                        .set_disputed(event.tx, true)
+                        .map_err(EventError::StateError)?;
+                    state.available -= record.amount;
+                    state.held += record.amount;
+                    self.activity.applied += 1;
+                    self.activity.disputed += record.amount;
+                    self.record(record.client, &event);
+                } else {
+                    self.park(event);
                 }
             }
 
             EventType::Resolve => {
-                if let Some(record) = self.deposits.get_mut(&event.tx) {
+                let record = self
+                    .deposits
+                    .get(event.tx)
+                    .map_err(EventError::StateError)?;
+                if let Some(record) = record {
                     if !record.is_disputed {
                         // If the tx isn't under dispute, you can ignore the resolve and assume this is an error
                         // on our partners' side.
+                        self.activity.ignored += 1;
                         return Ok(());
                     }
 
                     let state = self
                         .client_state
-                        .get_mut(&record.event.client)
-                        .ok_or(EventError::UnknownClient(event.client))?;
+                        .get_mut(record.client)
+                        .ok_or(EventError::UnknownClient(event.client, event.tx))?;
 
-                    record.is_disputed = false;
-                    state.held -= record.event.amount;
-                    state.available += record.event.amount;
+                    self.deposits
+                        .set_disputed(event.tx, false)
+                        .map_err(EventError::StateError)?;
+                    state.held -= record.amount;
+                    state.available += record.amount;
+                    self.activity.applied += 1;
+                    self.activity.resolved += record.amount;
+                    self.record(record.client, &event);
+                } else {
+                    self.park(event);
                 }
             }
 
             EventType::Chargeback => {
-                if let Some(record) = self.deposits.get_mut(&event.tx) {
+                let record = self
+                    .deposits
+                    .get(event.tx)
+                    .map_err(EventError::StateError)?;
+                if let Some(record) = record {
                     if !record.is_disputed {
                         // If the tx isn't under dispute, you can ignore the resolve and assume this is an error
                         // on our partners' side.
+                        self.activity.ignored += 1;
                         return Ok(());
                     }
 
                     let state = self
                         .client_state
-                        .get_mut(&record.event.client)
-                        .ok_or(EventError::UnknownClient(event.client))?;
+                        .get_mut(record.client)
+                        .ok_or(EventError::UnknownClient(event.client, event.tx))?;
 
-                    record.is_disputed = false;
-                    state.held -= record.event.amount;
+                    self.deposits
+                        .set_disputed(event.tx, false)
+                        .map_err(EventError::StateError)?;
+                    state.held -= record.amount;
+                    if !state.locked {
+                        self.activity.newly_locked += 1;
+                    }
                     state.locked = true;
+                    self.activity.applied += 1;
+                    self.activity.charged_back += record.amount;
+                    self.record(record.client, &event);
+                } else {
+                    self.park(event);
                 }
             }
         }
 
         Ok(())
     }
+}
 
-    fn emit_state(&self) -> Box<dyn '_ + Iterator<Item = crate::primitives::SerializeClientState>> {
-        Box::new(
-            self.client_state
-                .iter()
-                .map(|(client_id, client_state)| client_state.to_serialize(*client_id)),
-        )
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::Amount;
+
+    fn event(event_type: EventType, client: u16, tx: u32, amount: &str) -> Event {
+        Event {
+            event_type,
+            client: client.into(),
+            tx: tx.into(),
+            amount: amount.parse().expect("test amounts are valid"),
+        }
+    }
+
+    fn run(state: &mut MemoryState, events: Vec<Event>) -> Vec<EventError<io::Error>> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(events.len() + 16);
+        crate::process_events(state, events, Some(tx));
+        rx.into_iter().collect()
+    }
+
+    #[test]
+    fn pending_dispute_replays_when_deposit_arrives() {
+        let mut state = MemoryState::with_pending(PendingConfig::default());
+        let errors = run(
+            &mut state,
+            vec![
+                event(EventType::Dispute, 2, 1, "0"),
+                event(EventType::Resolve, 2, 1, "0"),
+                event(EventType::Dispute, 2, 1, "0"),
+                event(EventType::Deposit, 1, 1, "1.5"),
+            ],
+        );
+
+        assert!(errors.is_empty());
+        let client = &state.client_state[&1.into()];
+        assert_eq!(client.available, Amount::ZERO);
+        assert_eq!(client.held, "1.5".parse().expect("valid amount"));
+        assert!(state.deposits.record(1.into()).is_disputed);
+    }
+
+    #[test]
+    fn pending_overflow_expires_oldest() {
+        let mut state = MemoryState::with_pending(PendingConfig {
+            max_count: 1,
+            ..PendingConfig::default()
+        });
+        let errors = run(
+            &mut state,
+            vec![
+                event(EventType::Dispute, 2, 1, "0"),
+                event(EventType::Dispute, 2, 2, "0"),
+                event(EventType::Deposit, 1, 1, "1"),
+                event(EventType::Deposit, 1, 2, "1"),
+            ],
+        );
+
+        assert!(matches!(
+            errors.as_slice(),
+            [EventError::PendingReferenceExpired(client, tx)] if *client == 2.into() && *tx == 1.into()
+        ));
+        assert!(!state.deposits.record(1.into()).is_disputed);
+        assert!(state.deposits.record(2.into()).is_disputed);
+    }
+
+    #[test]
+    fn pending_events_expire_at_end_of_stream() {
+        let mut state = MemoryState::with_pending(PendingConfig::default());
+        let errors = run(&mut state, vec![event(EventType::Chargeback, 3, 7, "0")]);
+
+        assert!(matches!(
+            errors.as_slice(),
+            [EventError::PendingReferenceExpired(client, tx)] if *client == 3.into() && *tx == 7.into()
+        ));
+        assert_eq!(state.pending.as_ref().map(PendingQueue::len), Some(0));
+    }
+
+    #[test]
+    fn history_records_applied_events_with_balances() {
+        let mut state = MemoryState::with_pending(PendingConfig::default()).with_history();
+        let errors = run(
+            &mut state,
+            vec![
+                event(EventType::Deposit, 1, 1, "2"),
+                event(EventType::Withdrawal, 1, 2, "5"),
+                event(EventType::Withdrawal, 1, 3, "0.5"),
+                event(EventType::Dispute, 2, 4, "0"),
+                event(EventType::Resolve, 1, 1, "0"),
+                event(EventType::Deposit, 1, 4, "1"),
+            ],
+        );
+        assert_eq!(errors.len(), 1, "the first withdrawal is rejected");
+
+        let history: Vec<_> = state
+            .history(1.into())
+            .expect("history is kept")
+            .map(|entry| {
+                (
+                    entry.seq,
+                    entry.event.tx,
+                    entry.state.available,
+                    entry.state.held,
+                )
+            })
+            .collect();
+        let amount = |amount: &str| amount.parse::<Amount>().expect("valid amount");
+        assert_eq!(
+            history,
+            [
+                (1, 1.into(), amount("2"), Amount::ZERO),
+                (3, 3.into(), amount("1.5"), Amount::ZERO),
+                // the deferred dispute, from another client, is applied with the deposit it awaited
+                (6, 4.into(), amount("2.5"), Amount::ZERO),
+                (6, 4.into(), amount("1.5"), amount("1")),
+            ]
+        );
+        assert_eq!(state.history(2.into()).map(Iterator::count), Some(0));
+        assert!(MemoryState::default().history(1.into()).is_none());
+    }
+
+    #[test]
+    fn unknown_references_are_dropped_without_pending_queue() {
+        let mut state = MemoryState::default();
+        let errors = run(
+            &mut state,
+            vec![
+                event(EventType::Dispute, 2, 1, "0"),
+                event(EventType::Deposit, 1, 1, "1"),
+            ],
+        );
+
+        assert!(errors.is_empty());
+        assert!(!state.deposits.record(1.into()).is_disputed);
     }
 }
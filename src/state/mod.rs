@@ -1,10 +1,103 @@
+pub mod dense;
+pub mod deposits;
 pub mod memory;
+pub mod snapshot;
+
+use std::cmp::Ordering;
+
+use serde::Serialize;
 
 use crate::{
-    primitives::{Event, SerializeClientState},
+    primitives::{Amount, ClientId, Event, SerializeClientState},
     EventError,
 };
 
+/// Activity tallies the effects of the events a state manager has handled.
+///
+/// Events which were rejected are not counted here; they are reported as errors instead.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Activity {
+    /// Events which changed some client's state.
+    pub applied: usize,
+    /// Events which were accepted, but had no effect: resolves and chargebacks of transactions
+    /// which are not under dispute, and references to unknown transactions when they are not
+    /// deferred.
+    pub ignored: usize,
+    pub deposited: Amount,
+    pub withdrawn: Amount,
+    pub disputed: Amount,
+    pub resolved: Amount,
+    pub charged_back: Amount,
+    /// Accounts which were locked by a chargeback, having previously been unlocked.
+    pub newly_locked: usize,
+}
+
+impl std::ops::AddAssign for Activity {
+    fn add_assign(&mut self, other: Self) {
+        self.applied += other.applied;
+        self.ignored += other.ignored;
+        self.deposited += other.deposited;
+        self.withdrawn += other.withdrawn;
+        self.disputed += other.disputed;
+        self.resolved += other.resolved;
+        self.charged_back += other.charged_back;
+        self.newly_locked += other.newly_locked;
+    }
+}
+
+impl std::iter::Sum for Activity {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Activity::default(), |mut sum, activity| {
+            sum += activity;
+            sum
+        })
+    }
+}
+
+/// HistoryEntry records an event which was applied to a client, and its effect.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistoryEntry {
+    /// The number of events the state manager had handled when this one was applied, counting
+    /// from 1. A deferred reference is applied when the deposit it waited on arrives, so it shares
+    /// the number of that deposit.
+    pub seq: u64,
+    pub event: Event,
+    /// The state of the client once the event was applied.
+    pub state: SerializeClientState,
+}
+
+/// A column by which emitted client state can be sorted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Client,
+    Available,
+    Held,
+    Total,
+}
+
+impl SortKey {
+    /// Compare two clients in ascending order of this column, breaking ties by client id.
+    pub fn compare(self, a: &SerializeClientState, b: &SerializeClientState) -> Ordering {
+        let column = match self {
+            SortKey::Client => Ordering::Equal,
+            SortKey::Available => a.available.cmp(&b.available),
+            SortKey::Held => a.held.cmp(&b.held),
+            SortKey::Total => a.total.cmp(&b.total),
+        };
+        column.then(a.client.cmp(&b.client))
+    }
+}
+
+/// Collect and sort `clients` by `key`.
+pub fn sort_clients<'a>(
+    clients: impl Iterator<Item = SerializeClientState>,
+    key: SortKey,
+) -> Box<dyn 'a + Iterator<Item = SerializeClientState>> {
+    let mut clients: Vec<_> = clients.collect();
+    clients.sort_unstable_by(|a, b| key.compare(a, b));
+    Box::new(clients.into_iter())
+}
+
 /// A StateManager can update global state appropriately in response to events.
 pub trait StateManager {
     /// This error type should cover all errors generated by the IO aspect of the
@@ -14,8 +107,77 @@ pub trait StateManager {
     /// This function updates global state appropriately in response to incoming events.
     fn handle_event(&mut self, event: Event) -> Result<(), EventError<Self::Err>>;
 
+    /// This function returns errors which arose outside the direct handling of an event.
+    ///
+    /// For example, a state manager which defers events referencing unknown transactions
+    /// reports here when those deferred events are replayed unsuccessfully or expire.
+    fn take_deferred_errors(&mut self) -> Vec<EventError<Self::Err>> {
+        Vec::new()
+    }
+
+    /// This function signals that the event stream has ended.
+    ///
+    /// Any errors it produces are reported through `take_deferred_errors`.
+    fn finish(&mut self) {}
+
+    /// This function tallies the effects of the events handled so far.
+    ///
+    /// State managers which do not keep a tally report no activity.
+    fn activity(&self) -> Activity {
+        Activity::default()
+    }
+
     /// This function emits global state as an unordered set of records.
     ///
     /// The box will hopefully become unnecessary in future versions of Rust.
     fn emit_state(&self) -> Box<dyn '_ + Iterator<Item = SerializeClientState>>;
+
+    /// This function emits global state in ascending order of `key`, breaking ties by client id.
+    ///
+    /// By default, the whole state is collected and sorted. State managers which can produce
+    /// records in order should override this to stream them instead.
+    fn emit_sorted(&self, key: SortKey) -> Box<dyn '_ + Iterator<Item = SerializeClientState>> {
+        sort_clients(self.emit_state(), key)
+    }
+
+    /// This function returns the events applied to `client`, in the order they were applied.
+    ///
+    /// Events which were rejected or ignored are not part of the history. State managers which
+    /// keep no history, or were not asked to, return `None`.
+    fn history(&self, client: ClientId) -> Option<Box<dyn '_ + Iterator<Item = HistoryEntry>>> {
+        let _ = client;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::ClientState;
+
+    #[test]
+    fn ties_are_broken_by_client_id() {
+        let client = |id: u16, held: &str| {
+            ClientState {
+                held: held.parse().expect("valid amount"),
+                ..ClientState::default()
+            }
+            .to_serialize(id.into())
+        };
+        let clients = vec![
+            client(3, "1"),
+            client(1, "2"),
+            client(2, "1"),
+            client(0, "0.5"),
+        ];
+        let order = |key| -> Vec<u16> {
+            sort_clients(clients.clone().into_iter(), key)
+                .map(|client| client.client.into())
+                .collect()
+        };
+        assert_eq!(order(SortKey::Client), [0, 1, 2, 3]);
+        assert_eq!(order(SortKey::Held), [0, 2, 3, 1]);
+        assert_eq!(order(SortKey::Total), [0, 2, 3, 1]);
+        assert_eq!(order(SortKey::Available), [0, 1, 2, 3]);
+    }
 }
@@ -0,0 +1,213 @@
+//! Snapshots of the complete state of a state manager, from which it can be rebuilt exactly.
+//!
+//! Unlike the client state which is emitted at the end of a run, a snapshot includes everything
+//! needed to carry on processing: deposit records and their dispute status, deferred references,
+//! and the activity tallied so far. Snapshots are binary, little-endian, and written as a stream, so
+//! deposit records which have been spilled to disk are never loaded into memory to be written.
+
+use std::io::{self, Read, Write};
+
+use crate::primitives::{Amount, Event, EventType};
+
+/// A Snapshot can be written out and read back in to rebuild the same state.
+pub trait Snapshot {
+    /// Write the complete state to `out`.
+    fn write_snapshot(&self, out: &mut dyn Write) -> io::Result<()>;
+
+    /// Replace the state with one previously written by `write_snapshot`.
+    ///
+    /// Configuration, such as the bounds on deferred references or where deposits spill to, is
+    /// not part of a snapshot; it is kept from the state being read into.
+    fn read_snapshot(&mut self, input: &mut dyn Read) -> io::Result<()>;
+}
+
+/// Snapshots begin with a tag identifying the kind of state which wrote them.
+pub(crate) const MEMORY_TAG: u8 = 0;
+pub(crate) const SHARDED_TAG: u8 = 1;
+
+/// Check that a snapshot was written by the expected kind of state.
+pub(crate) fn expect_tag(input: &mut dyn Read, expected: u8) -> io::Result<()> {
+    match read_u8(input)? {
+        tag if tag == expected => Ok(()),
+        SHARDED_TAG => Err(invalid_data("snapshot is of sharded state")),
+        MEMORY_TAG => Err(invalid_data("snapshot is of unsharded state")),
+        _ => Err(invalid_data("snapshot is of an unknown kind of state")),
+    }
+}
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+macro_rules! le_codec {
+    ($write:ident, $read:ident, $int:ty) => {
+        pub(crate) fn $write(out: &mut dyn Write, value: $int) -> io::Result<()> {
+            out.write_all(&value.to_le_bytes())
+        }
+
+        pub(crate) fn $read(input: &mut dyn Read) -> io::Result<$int> {
+            let mut buf = [0; std::mem::size_of::<$int>()];
+            input.read_exact(&mut buf)?;
+            Ok(<$int>::from_le_bytes(buf))
+        }
+    };
+}
+
+le_codec!(write_u8, read_u8, u8);
+le_codec!(write_u16, read_u16, u16);
+le_codec!(write_u32, read_u32, u32);
+le_codec!(write_u64, read_u64, u64);
+
+pub(crate) fn write_amount(out: &mut dyn Write, amount: Amount) -> io::Result<()> {
+    write_u64(out, amount.to_raw())
+}
+
+pub(crate) fn read_amount(input: &mut dyn Read) -> io::Result<Amount> {
+    read_u64(input).map(Amount::from_raw)
+}
+
+pub(crate) fn write_event(out: &mut dyn Write, event: &Event) -> io::Result<()> {
+    write_u8(out, event.event_type as u8)?;
+    write_u16(out, event.client.into())?;
+    write_u32(out, event.tx.into())?;
+    write_amount(out, event.amount)
+}
+
+pub(crate) fn read_event(input: &mut dyn Read) -> io::Result<Event> {
+    let event_type = *EventType::ALL
+        .get(read_u8(input)? as usize)
+        .ok_or_else(|| invalid_data("unknown event type"))?;
+    Ok(Event {
+        event_type,
+        client: read_u16(input)?.into(),
+        tx: read_u32(input)?.into(),
+        amount: read_amount(input)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        feed_records,
+        primitives::tests::{arb_dense_event, valid_events},
+        process_events,
+        sharded::ShardedState,
+        state::{
+            deposits::SpillConfig,
+            memory::{MemoryState, PendingConfig},
+            SortKey, StateManager,
+        },
+    };
+    use proptest::prelude::*;
+
+    /// Feed events without finishing, so that deferred references remain pending.
+    fn feed<S: StateManager>(state: &mut S, events: Vec<Event>) -> crate::ProcessCounts {
+        feed_records(state, events.into_iter().map(Ok), None)
+    }
+
+    fn round_trip<S: Snapshot>(state: &S, into: &mut S) {
+        let mut buf = Vec::new();
+        state
+            .write_snapshot(&mut buf)
+            .expect("writing to a vec succeeds");
+        into.read_snapshot(&mut buf.as_slice())
+            .expect("a snapshot just written can be read");
+    }
+
+    fn make_state() -> MemoryState {
+        let spill = SpillConfig {
+            memory_ceiling: 256,
+            directory: std::env::temp_dir(),
+        };
+        MemoryState::new(Some(PendingConfig::default()), Some(spill)).with_history()
+    }
+
+    fn histories<S: StateManager>(state: &S) -> Vec<Vec<crate::state::HistoryEntry>> {
+        (0..20)
+            .map(|client| {
+                state
+                    .history(client.into())
+                    .expect("history is kept")
+                    .collect()
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn restored_state_carries_on_identically(
+            events in proptest::collection::vec(arb_dense_event(100), 0..300),
+            split in any::<prop::sample::Index>(),
+        ) {
+            let mut before = valid_events(&mut make_state(), events);
+            let after = before.split_off(split.index(before.len() + 1));
+
+            let mut original = make_state();
+            feed(&mut original, before);
+            let mut restored = make_state();
+            round_trip(&original, &mut restored);
+
+            let expect = process_events(&mut original, after.clone(), None);
+            prop_assert_eq!(process_events(&mut restored, after, None), expect);
+            prop_assert_eq!(restored.activity(), original.activity());
+            prop_assert_eq!(histories(&restored), histories(&original));
+            prop_assert_eq!(
+                restored.emit_sorted(SortKey::Client).collect::<Vec<_>>(),
+                original.emit_sorted(SortKey::Client).collect::<Vec<_>>()
+            );
+        }
+
+        #[test]
+        fn sharded_state_round_trips(
+            events in proptest::collection::vec(arb_dense_event(100), 0..150),
+            shards in 1_usize..5,
+        ) {
+            let events = valid_events(&mut ShardedState::new(shards, make_state), events);
+            let mut original = ShardedState::new(shards, make_state);
+            feed(&mut original, events);
+            let mut restored = ShardedState::new(shards, make_state);
+            round_trip(&original, &mut restored);
+
+            prop_assert_eq!(restored.activity(), original.activity());
+            prop_assert_eq!(histories(&restored), histories(&original));
+            prop_assert_eq!(
+                restored.emit_sorted(SortKey::Client).collect::<Vec<_>>(),
+                original.emit_sorted(SortKey::Client).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn snapshots_are_only_read_by_the_same_kind_of_state() {
+        let mut buf = Vec::new();
+        ShardedState::new(2, make_state)
+            .write_snapshot(&mut buf)
+            .expect("writing to a vec succeeds");
+        assert!(make_state().read_snapshot(&mut buf.as_slice()).is_err());
+        assert!(ShardedState::new(3, make_state)
+            .read_snapshot(&mut buf.as_slice())
+            .is_err());
+    }
+
+    #[test]
+    fn history_is_only_read_into_state_which_keeps_it() {
+        let mut state = make_state();
+        feed(&mut state, vec![deposit()]);
+        let mut buf = Vec::new();
+        state
+            .write_snapshot(&mut buf)
+            .expect("writing to a vec succeeds");
+        let mut without = MemoryState::with_pending(PendingConfig::default());
+        assert!(without.read_snapshot(&mut buf.as_slice()).is_err());
+    }
+
+    fn deposit() -> Event {
+        Event {
+            event_type: EventType::Deposit,
+            client: 1.into(),
+            tx: 1.into(),
+            amount: "1".parse().expect("valid amount"),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Account statements: the events applied to a client over a range, with running balances.
+//!
+//! A statement is built from the client's history (see `StateManager::history`), so the state must
+//! have been keeping one. Events carry no timestamps, so the range is of event sequence numbers.
+
+use std::ops::{Bound, RangeBounds};
+
+use crate::{
+    primitives::{ClientId, ClientState, SerializeClientState},
+    state::HistoryEntry,
+};
+
+/// Statement lists the events applied to a client within a range, between the client's balances
+/// before the first of them and after the last.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub client: ClientId,
+    /// The client's state before the range: after the last event preceding it, or empty if there
+    /// was none.
+    pub opening: SerializeClientState,
+    /// Each event applied within the range, with the client's state after it.
+    pub entries: Vec<HistoryEntry>,
+    /// The client's state after the range; the opening state if no event fell within it.
+    pub closing: SerializeClientState,
+}
+
+impl Statement {
+    /// Build the statement of `client` over the events whose sequence numbers fall within `range`.
+    ///
+    /// `history` must be in the order the events were applied.
+    pub fn new(
+        client: ClientId,
+        history: impl IntoIterator<Item = HistoryEntry>,
+        range: impl RangeBounds<u64>,
+    ) -> Self {
+        let mut opening = ClientState::default().to_serialize(client);
+        let mut entries = Vec::new();
+        for entry in history {
+            if range.contains(&entry.seq) {
+                entries.push(entry);
+            } else if precedes(&range, entry.seq) {
+                opening = entry.state;
+            } else {
+                break;
+            }
+        }
+        let closing = entries
+            .last()
+            .map_or_else(|| opening.clone(), |entry| entry.state.clone());
+        Statement {
+            client,
+            opening,
+            entries,
+            closing,
+        }
+    }
+}
+
+/// Whether `seq` comes before the start of `range`.
+fn precedes(range: &impl RangeBounds<u64>, seq: u64) -> bool {
+    match range.start_bound() {
+        Bound::Included(start) => seq < *start,
+        Bound::Excluded(start) => seq <= *start,
+        Bound::Unbounded => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        primitives::{Event, EventType},
+        process_events,
+        state::{memory::MemoryState, StateManager},
+    };
+
+    fn event(event_type: EventType, tx: u32, amount: &str) -> Event {
+        Event {
+            event_type,
+            client: 1.into(),
+            tx: tx.into(),
+            amount: amount.parse().expect("test amounts are valid"),
+        }
+    }
+
+    fn history() -> Vec<HistoryEntry> {
+        let mut state = MemoryState::default().with_history();
+        process_events(
+            &mut state,
+            vec![
+                event(EventType::Deposit, 1, "10"),
+                event(EventType::Deposit, 2, "5"),
+                event(EventType::Withdrawal, 3, "3"),
+                event(EventType::Dispute, 2, "0"),
+                event(EventType::Chargeback, 2, "0"),
+            ],
+            None,
+        );
+        state.history(1.into()).expect("history is kept").collect()
+    }
+
+    fn amount(amount: &str) -> crate::primitives::Amount {
+        amount.parse().expect("test amounts are valid")
+    }
+
+    #[test]
+    fn statements_open_and_close_around_their_range() {
+        let statement = Statement::new(1.into(), history(), 3..=4);
+        assert_eq!(statement.opening.available, amount("15"));
+        let txs: Vec<_> = statement.entries.iter().map(|e| e.event.tx).collect();
+        assert_eq!(txs, [3.into(), 2.into()]);
+        assert_eq!(statement.closing.available, amount("7"));
+        assert_eq!(statement.closing.held, amount("5"));
+        assert_eq!(statement.closing.total, amount("12"));
+        assert!(!statement.closing.locked);
+
+        let whole = Statement::new(1.into(), history(), ..);
+        assert_eq!(whole.opening, ClientState::default().to_serialize(1.into()));
+        assert_eq!(whole.entries.len(), 5);
+        assert!(whole.closing.locked);
+    }
+
+    #[test]
+    fn empty_ranges_close_at_their_opening() {
+        let statement = Statement::new(1.into(), history(), 10..);
+        assert!(statement.entries.is_empty());
+        assert_eq!(statement.opening, statement.closing);
+        assert!(statement.closing.locked);
+    }
+}
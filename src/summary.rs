@@ -0,0 +1,180 @@
+//! Summarizing a run of the engine.
+
+use std::{fmt, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    output::fixed_point, primitives::Amount, state::StateManager, EventTypeCounts, ProcessCounts,
+    Reason, ReasonCounts,
+};
+
+/// Summary describes what happened during a run: what was read, what became of it, and how much
+/// money moved.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    /// Records read, whether or not they parsed.
+    pub records: usize,
+    /// Records which failed to parse.
+    pub malformed: usize,
+    /// Records which parsed, by event type.
+    pub event_types: EventTypeCounts,
+    /// Events which changed some client's state.
+    pub applied: usize,
+    /// Events which were accepted, but had no effect.
+    pub ignored: usize,
+    /// Events which were rejected, including deferred references which expired.
+    pub rejected: usize,
+    /// Errors reported, by reason, including malformed records.
+    pub rejections: ReasonCounts,
+    pub deposited: Amount,
+    pub withdrawn: Amount,
+    /// Funds held across all clients at the end of the run.
+    pub held: Amount,
+    pub charged_back: Amount,
+    /// Accounts which were locked during the run.
+    pub newly_locked: usize,
+    pub elapsed_seconds: f64,
+    pub records_per_second: f64,
+}
+
+impl Summary {
+    /// Summarize a run which produced `counts` and left `state` behind, taking `elapsed`.
+    pub fn new<State>(counts: &ProcessCounts, state: &State, elapsed: Duration) -> Self
+    where
+        State: StateManager + ?Sized,
+    {
+        let activity = state.activity();
+        let malformed = counts.reasons[Reason::Parse];
+        let held = state
+            .emit_state()
+            .fold(Amount::ZERO, |held, client| held + client.held);
+        let elapsed_seconds = elapsed.as_secs_f64();
+        let records_per_second = if elapsed_seconds > 0.0 {
+            counts.records as f64 / elapsed_seconds
+        } else {
+            0.0
+        };
+
+        Summary {
+            records: counts.records,
+            malformed,
+            event_types: counts.event_types,
+            applied: activity.applied,
+            ignored: activity.ignored,
+            rejected: counts.errors - malformed,
+            rejections: counts.reasons,
+            deposited: activity.deposited,
+            withdrawn: activity.withdrawn,
+            held,
+            charged_back: activity.charged_back,
+            newly_locked: activity.newly_locked,
+            elapsed_seconds,
+            records_per_second,
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let event_types: Vec<_> = self
+            .event_types
+            .iter()
+            .map(|(event_type, count)| format!("{count} {event_type}"))
+            .collect();
+        let rejections: Vec<_> = self
+            .rejections
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(reason, count)| format!("{count} {reason}"))
+            .collect();
+
+        writeln!(
+            f,
+            "records:      {} ({} malformed)",
+            self.records, self.malformed
+        )?;
+        writeln!(f, "events:       {}", event_types.join(", "))?;
+        writeln!(
+            f,
+            "outcomes:     {} applied, {} ignored, {} rejected",
+            self.applied, self.ignored, self.rejected
+        )?;
+        if !rejections.is_empty() {
+            writeln!(f, "rejections:   {}", rejections.join(", "))?;
+        }
+        writeln!(f, "deposited:    {}", fixed_point(self.deposited))?;
+        writeln!(f, "withdrawn:    {}", fixed_point(self.withdrawn))?;
+        writeln!(f, "held:         {}", fixed_point(self.held))?;
+        writeln!(f, "charged back: {}", fixed_point(self.charged_back))?;
+        writeln!(f, "newly locked: {} accounts", self.newly_locked)?;
+        write!(
+            f,
+            "throughput:   {:.0} records/s over {:.3}s",
+            self.records_per_second, self.elapsed_seconds
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        input::read_events,
+        primitives::{tests::arb_event, EventType},
+        process_records_summarized,
+        state::memory::{MemoryState, PendingConfig},
+    };
+    use proptest::prelude::*;
+
+    fn amount(amount: &str) -> Amount {
+        amount.parse().expect("test amounts are valid")
+    }
+
+    #[test]
+    fn summary_of_a_small_run() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10.0\n\
+            deposit,2,2,5.0\n\
+            dispute,1,1,\n\
+            deposit,1,3,3.0\n\
+            withdrawal,1,4,2.5\n\
+            withdrawal,2,5,50.0\n\
+            resolve,2,2,\n\
+            dispute,2,2,\n\
+            chargeback,2,2,\n\
+            deposit,1,1,1.0\n\
+            refund,1,6,1.0\n";
+        let records = read_events(&crate::mapping::InputMapping::default(), input.as_bytes());
+        let summary = process_records_summarized(&mut MemoryState::default(), records, None);
+
+        assert_eq!(summary.records, 11);
+        assert_eq!(summary.malformed, 1);
+        assert_eq!(summary.event_types[EventType::Deposit], 4);
+        assert_eq!(summary.event_types[EventType::Chargeback], 1);
+        assert_eq!(summary.applied, 7);
+        assert_eq!(summary.ignored, 1);
+        assert_eq!(summary.rejected, 2);
+        assert_eq!(summary.rejections[Reason::InsufficientFunds], 1);
+        assert_eq!(summary.rejections[Reason::DuplicateTransactionId], 1);
+        assert_eq!(summary.deposited, amount("18"));
+        assert_eq!(summary.withdrawn, amount("2.5"));
+        assert_eq!(summary.held, amount("10"));
+        assert_eq!(summary.charged_back, amount("5"));
+        assert_eq!(summary.newly_locked, 1);
+    }
+
+    proptest! {
+        #[test]
+        fn every_event_has_one_outcome(
+            events in proptest::collection::vec(arb_event(20, 1000.0), 0..200),
+            max_age in 1_u64..20,
+        ) {
+            let config = PendingConfig { max_age, ..PendingConfig::default() };
+            let mut state = MemoryState::with_pending(config);
+            let summary = process_records_summarized(&mut state, events.into_iter().map(Ok), None);
+
+            prop_assert_eq!(summary.applied + summary.ignored + summary.rejected, summary.records);
+        }
+    }
+}